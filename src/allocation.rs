@@ -0,0 +1,391 @@
+use std::fmt;
+
+use crate::rounding::to_i64;
+use crate::{Date, Fraction, Period, BASE_CURRENCY};
+
+/// The result of solving a plan for one month.
+#[derive(Debug, Clone)]
+pub struct Allocation {
+    /// The month that was solved, if the plan has any balance dates.
+    pub date: Option<Date>,
+    pub sources: Vec<SourceAllocation>,
+    pub sinks: Vec<SinkAllocation>,
+    pub transfers: Vec<TransferAllocation>,
+    /// The smallest transfer worth making, if the plan sets one.
+    pub minimum: Option<Fraction>,
+}
+
+impl Allocation {
+    /// Whether commitments and needs match up this month.
+    pub fn verdict(&self) -> Verdict {
+        let overcommitted = self.sources.iter().any(|s| s.unspent > Fraction::from(0));
+        let underfunded = self.sinks.iter().any(|s| s.is_short());
+        match (overcommitted, underfunded) {
+            (false, false) => Verdict::Balanced,
+            (true, false) => Verdict::Overcommitted,
+            (false, true) => Verdict::Underfunded,
+            (true, true) => Verdict::Mismatched,
+        }
+    }
+}
+
+/// How commitments and needs match up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Every need is met and every commitment is spent.
+    Balanced,
+    /// Every need is met and some commitments are left over.
+    Overcommitted,
+    /// Some needs are short and no commitment is left over.
+    Underfunded,
+    /// Some needs are short while commitments are left over that no flow lets reach them.
+    Mismatched,
+}
+
+impl Verdict {
+    /// The verdict as a single lowercase word.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Balanced => "balanced",
+            Verdict::Overcommitted => "overcommitted",
+            Verdict::Underfunded => "underfunded",
+            Verdict::Mismatched => "mismatched",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let why = match self {
+            Verdict::Balanced => "every need is met and every commitment is spent",
+            Verdict::Overcommitted => "every need is met and money is left over",
+            Verdict::Underfunded => "needs are short and more contributors are needed",
+            Verdict::Mismatched => "needs are short while money is left over that no flow lets reach them",
+        };
+        write!(f, "{}, {}", self.as_str(), why)
+    }
+}
+
+/// What a source committed and how much of it no sink could take. Amounts are per month.
+#[derive(Debug, Clone)]
+pub struct SourceAllocation {
+    pub name: String,
+    /// The periodic commitment, zero in months outside of its window.
+    pub committed: Fraction,
+    /// One-time gifts due this month and what was carried over from earlier ones.
+    pub one_time: Fraction,
+    /// The currency the source pays in, amounts are in the base currency.
+    pub currency: String,
+    /// The period the source committed in.
+    pub period: Period,
+    /// The first month the commitment holds for.
+    pub from: Option<Date>,
+    /// The last month the commitment holds for.
+    pub until: Option<Date>,
+    pub unspent: Fraction,
+    /// The unspent amount in whole cents, the commitment less the cents of its transfers.
+    pub unspent_cents: i64,
+    /// The part of the unspent amount that carries over into the next month.
+    pub carried: Fraction,
+    /// Sinks the source only pays if they are fully funded and that weren't, so it didn't pay them.
+    pub withheld: Vec<String>,
+    /// Sinks the source didn't pay because it would have sent them less than the minimum transfer.
+    pub too_small: Vec<String>,
+}
+
+/// What a sink needs, has and receives. Amounts are per month.
+#[derive(Debug, Clone)]
+pub struct SinkAllocation {
+    pub name: String,
+    /// Infinite for sinks that only appear in flows.
+    pub need: Fraction,
+    /// `None` for one-off needs.
+    pub period: Option<Period>,
+    pub balance: Fraction,
+    pub received: Fraction,
+    /// What it received in whole cents, the sum of its transfers' cents.
+    pub received_cents: i64,
+    /// Cents the rounded transfers to this sink are over (positive) or under what it
+    /// receives rounded, when they can't be made to agree. Normally zero.
+    pub leftover_cents: i64,
+    /// Date of the balance, if one was given.
+    pub date: Option<Date>,
+    /// The month a one-off need should be funded by.
+    pub deadline: Option<Date>,
+}
+
+impl SinkAllocation {
+    /// A one-off need that has been covered by its balance and what it received.
+    pub fn is_funded(&self) -> bool {
+        self.period.is_none() && self.need != Fraction::infinity() && self.balance.clone() + self.received.clone() >= self.need
+    }
+
+    /// A need that is still missing money after this month.
+    pub fn is_short(&self) -> bool {
+        self.need != Fraction::infinity() && self.remaining() > Fraction::from(0)
+    }
+
+    /// What is still missing after this month in whole cents, from the need and balance
+    /// rounded and the cents received, so it adds up with them. Zero for sinks that only
+    /// appear in flows.
+    pub fn shortfall_cents(&self) -> i64 {
+        if self.need == Fraction::infinity() {
+            return 0
+        }
+        (to_cents(self.need.clone()) - to_cents(self.balance.clone()) - self.received_cents).max(0)
+    }
+
+    /// What is still missing after this month, infinite for sinks that only appear in flows.
+    pub fn remaining(&self) -> Fraction {
+        let remaining = self.need.clone() - self.balance.clone() - self.received.clone();
+        if remaining < Fraction::from(0) {
+            Fraction::from(0)
+        } else {
+            remaining
+        }
+    }
+}
+
+/// Money sent from one source to one sink. The amount is per month.
+#[derive(Debug, Clone)]
+pub struct TransferAllocation {
+    pub source: String,
+    pub sink: String,
+    pub amount: Fraction,
+    /// The amount in whole cents, rounded so that a source's transfers add up to
+    /// what it sends and a sink's add up to what it receives.
+    pub cents: i64,
+    /// What the bank keeps of the amount, the sink receives the rest.
+    pub fee: Fraction,
+    pub fee_cents: i64,
+    /// Whether the source sent this to match what others gave to the sink.
+    pub matching: bool,
+    /// The currency the source pays in.
+    pub currency: String,
+    /// The amount in the source's currency.
+    pub paid: Fraction,
+    /// The paid amount in whole cents, rounded so that a source's transfers add up to
+    /// what it pays. The same as `cents` in the base currency.
+    pub paid_cents: i64,
+    /// The period the source committed in.
+    pub period: Period,
+}
+
+impl TransferAllocation {
+    /// What arrives at the sink once the fee is taken.
+    pub fn net(&self) -> Fraction {
+        self.amount.clone() - self.fee.clone()
+    }
+
+    pub fn net_cents(&self) -> i64 {
+        self.cents - self.fee_cents
+    }
+}
+
+/// The result of solving a plan for consecutive months.
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub months: Vec<Allocation>,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // print what sinks receive
+        for s in &self.sinks {
+            write!(f, "{} <- {}/month{}", s.name, format_cents(s.received_cents), in_period(s.received.clone(), s.period))?;
+            if let Some(d) = &s.date {
+                write!(f, " for {}", d)?;
+            }
+            if let Some(d) = &s.deadline {
+                write!(f, ", due by {}", d)?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f)?;
+
+        // print transfers, grouped by source
+        for (i, t) in self.transfers.iter().enumerate() {
+            if t.currency == BASE_CURRENCY {
+                write!(f, "{} -> {}: {}/month{}", t.source, t.sink, format_cents(t.cents), in_period(t.amount.clone(), Some(t.period)))?;
+            } else {
+                // what the sender actually pays, in the currency and period they committed in
+                write!(
+                    f,
+                    "{} -> {}: {}/month, paid as {} {}/month{}",
+                    t.source, t.sink, format_cents(t.cents),
+                    format_cents(t.paid_cents), t.currency, in_period_in(t.paid.clone(), Some(t.period), &t.currency),
+                )?;
+            }
+            if t.matching {
+                write!(f, ", matching what others give")?;
+            }
+            if t.fee > Fraction::from(0) {
+                write!(f, ", fee {}, net {}/month", format_cents(t.fee_cents), format_cents(t.net_cents()))?;
+            }
+            if let Some(d) = self.sinks.iter().find(|s| s.name == t.sink).and_then(|s| s.date) {
+                write!(f, " for {}", d)?;
+            }
+            writeln!(f)?;
+
+            if self.transfers.get(i+1).is_none_or(|next| next.source != t.source) {
+                writeln!(f)?;
+            }
+        }
+
+        // money nobody could take and needs nobody could cover
+        let mut summary = false;
+        for s in &self.sources {
+            if s.unspent > Fraction::from(0) {
+                write!(f, "{} has {}/month unspent{}", s.name, format_cents(s.unspent_cents), in_period(s.unspent.clone(), Some(s.period)))?;
+                if s.carried > Fraction::from(0) {
+                    write!(f, ", {} carries over into next month", format_cents(to_cents(s.carried.clone())))?;
+                }
+                writeln!(f)?;
+                summary = true;
+            }
+        }
+        for s in &self.sources {
+            for k in &s.withheld {
+                writeln!(f, "{} doesn't pay {} because it isn't fully funded", s.name, k)?;
+                summary = true;
+            }
+            for k in &s.too_small {
+                writeln!(f, "{} doesn't pay {} because it would be below the minimum transfer", s.name, k)?;
+                summary = true;
+            }
+        }
+        for s in self.sinks.iter().filter(|s| s.is_short()) {
+            match s.period {
+                Some(_) => writeln!(f, "{} is {}/month short{}", s.name, format_cents(s.shortfall_cents()), in_period(s.remaining(), s.period))?,
+                None => writeln!(f, "{} is {} short", s.name, format_cents(s.shortfall_cents()))?,
+            }
+            summary = true;
+        }
+        if summary {
+            writeln!(f)?;
+        }
+        writeln!(f, "verdict: {}", self.verdict())?;
+
+        // sources don't pay what would be below the minimum, but matching follows what others give
+        if let Some(minimum) = &self.minimum {
+            for t in self.transfers.iter().filter(|t| t.matching && t.amount < *minimum) {
+                writeln!(
+                    f,
+                    "note: {} -> {} is below the minimum transfer of {}",
+                    t.source, t.sink, format_cents(to_cents(minimum.clone())),
+                )?;
+            }
+        }
+
+        // cents that rounding couldn't place without breaking a source's total
+        for s in &self.sinks {
+            if s.leftover_cents != 0 {
+                writeln!(
+                    f,
+                    "note: rounding leaves {} {} {}",
+                    s.name, format_cents(s.leftover_cents.abs()), if s.leftover_cents > 0 { "over" } else { "short" },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Simulation {
+    // prints what every sink receives per month and when one-off needs get funded
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sinks = match self.months.first() {
+            Some(a) => &a.sinks,
+            None => return Ok(()),
+        };
+
+        let mut rows: Vec<Vec<String>> = vec!();
+        let mut header = vec!("month".to_owned());
+        header.extend(sinks.iter().map(|s| s.name.clone()));
+        rows.push(header);
+
+        for (n, a) in self.months.iter().enumerate() {
+            let mut row = vec!(match a.date {
+                Some(d) => d.to_string(),
+                None => format!("{}", n+1),
+            });
+            row.extend(a.sinks.iter().map(|s| format_cents(s.received_cents)));
+            rows.push(row);
+        }
+
+        let widths: Vec<usize> = (0..rows[0].len()).map(|j| {
+            rows.iter().map(|r| r[j].chars().count()).max().unwrap()
+        }).collect();
+
+        for row in &rows {
+            write!(f, "{:<1$}", row[0], widths[0])?;
+            for (j, cell) in row.iter().enumerate().skip(1) {
+                write!(f, "  {:>1$}", cell, widths[j])?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f)?;
+
+        let last = self.months.last().unwrap();
+        for (i, s) in last.sinks.iter().enumerate() {
+            if s.period.is_some() || s.need == Fraction::infinity() {
+                continue
+            }
+            let funded = self.months.iter().find(|a| a.sinks[i].is_funded());
+            match funded {
+                Some(a) => match a.date {
+                    Some(d) => write!(f, "{} is fully funded in {}", s.name, d)?,
+                    None => write!(f, "{} is fully funded", s.name)?,
+                },
+                None => write!(
+                    f,
+                    "{} is still {} short after {} months",
+                    s.name, format_cents(s.shortfall_cents()), self.months.len(),
+                )?,
+            }
+            if let Some(deadline) = s.deadline {
+                let late = funded.and_then(|a| a.date).is_none_or(|d| d.after(&deadline));
+                write!(f, ", {} its deadline {}", if late { "missing" } else { "meeting" }, deadline)?;
+            }
+            writeln!(f)?;
+        }
+
+        for s in last.sources.iter().filter(|s| s.unspent > Fraction::from(0)) {
+            writeln!(f, "{} has {}/month unspent at the end{}", s.name, format_cents(s.unspent_cents), in_period(s.unspent.clone(), Some(s.period)))?;
+        }
+        match last.date {
+            Some(d) => writeln!(f, "verdict for {}: {}", d, last.verdict())?,
+            None => writeln!(f, "verdict for the last month: {}", last.verdict())?,
+        }
+
+        Ok(())
+    }
+}
+
+// rounds to whole cents, halves away from zero
+pub(crate) fn to_cents(f: Fraction) -> i64 {
+    to_i64((f * Fraction::from(100)).round())
+}
+
+pub(crate) fn format_cents(c: i64) -> String {
+    format!("{}{}.{:02}", if c < 0 { "-" } else { "" }, c.abs() / 100, c.abs() % 100)
+}
+
+// restates a monthly amount in the period it was declared with, e.g. " (1200.00/year)"
+fn in_period(amount: Fraction, period: Option<Period>) -> String {
+    match period {
+        Some(p) if p != Period::Month => format!(" ({}/{})", format_cents(to_cents(p.per_period(amount))), p),
+        _ => String::new(),
+    }
+}
+
+// the same for amounts in another currency, e.g. " (1200.00 USD/year)"
+fn in_period_in(amount: Fraction, period: Option<Period>, currency: &str) -> String {
+    match period {
+        Some(p) if p != Period::Month => format!(" ({} {}/{})", format_cents(to_cents(p.per_period(amount))), currency, p),
+        _ => String::new(),
+    }
+}
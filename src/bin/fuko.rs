@@ -0,0 +1,174 @@
+// native command line front end, does the same as the wasm module but with files and stdio
+
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+const USAGE: &str = "usage: fuko [--format FORMAT] [--months N] [--explain] [FILE]
+       fuko fmt [--write] [FILE]
+
+Solves the plan in FILE, or read from stdin when FILE is - or missing.
+
+options:
+  --format FORMAT  text (default), json, csv for the transfers, sinks-csv or dot
+                   for a Graphviz graph of the flows in the first month
+  --months N       simulate N consecutive months, overriding `simulate` in the file
+  --explain        narrate every step of the solver on stderr
+
+fmt writes the file in canonical form to stdout, or back to FILE with --write.";
+
+enum Format {
+    Text,
+    Json,
+    Csv,
+    SinksCsv,
+    Dot,
+}
+
+fn main() {
+    process::exit(run(std::env::args().skip(1).collect()));
+}
+
+fn run(args: Vec<String>) -> i32 {
+    if args.first().map(|a| a.as_str()) == Some("fmt") {
+        return fmt(args.into_iter().skip(1).collect())
+    }
+
+    let mut path: Option<String> = None;
+    let mut months: Option<u32> = None;
+    let mut format = Format::Text;
+    let mut explain = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0
+            }
+            "--format" => match args.next().as_deref() {
+                Some("text") => format = Format::Text,
+                Some("json") => format = Format::Json,
+                Some("csv") => format = Format::Csv,
+                Some("sinks-csv") => format = Format::SinksCsv,
+                Some("dot") => format = Format::Dot,
+                _ => return usage("--format needs one of text, json, csv, sinks-csv or dot"),
+            },
+            "--months" => match args.next().and_then(|n| n.parse::<u32>().ok()) {
+                Some(n) if n > 0 && n <= fuko::MAX_MONTHS => months = Some(n),
+                _ => return usage(&format!("--months needs a number from 1 to {}", fuko::MAX_MONTHS)),
+            },
+            "--explain" => explain = true,
+            _ if arg.starts_with("--") => return usage(&format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return usage("only one file can be solved at a time"),
+        }
+    }
+
+    let bts = match read_input(path.as_deref()) {
+        Ok(bts) => bts,
+        Err(e) => {
+            eprintln!("fuko: {}: {}", path.as_deref().unwrap_or("stdin"), e);
+            return 1
+        }
+    };
+
+    let plan = match fuko::parse_bytes(&bts) {
+        Ok(plan) => plan,
+        Err(d) => {
+            eprint!("{}", d);
+            return 1
+        }
+    };
+
+    if !plan.warnings().is_empty() {
+        eprint!("{}", plan.warnings());
+    }
+
+    let months = months.or_else(|| plan.months());
+    if explain {
+        eprintln!("{}", plan.explain(months.unwrap_or(1)));
+    }
+
+    let out = match (months, format) {
+        (_, Format::Dot) => plan.to_dot(),
+        (Some(n), Format::Text) => plan.simulate(n).to_string(),
+        (Some(n), Format::Json) => plan.simulate(n).to_json(),
+        (Some(n), Format::Csv) => plan.simulate(n).transfers_csv(),
+        (Some(n), Format::SinksCsv) => plan.simulate(n).sinks_csv(),
+        (None, Format::Text) => plan.solve().to_string(),
+        (None, Format::Json) => plan.solve().to_json(),
+        (None, Format::Csv) => plan.solve().transfers_csv(),
+        (None, Format::SinksCsv) => plan.solve().sinks_csv(),
+    };
+    print!("{}", out);
+
+    0
+}
+
+fn fmt(args: Vec<String>) -> i32 {
+    let mut path: Option<String> = None;
+    let mut write = false;
+
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return 0
+            }
+            "--write" => write = true,
+            _ if arg.starts_with("--") => return usage(&format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return usage("only one file can be formatted at a time"),
+        }
+    }
+
+    let file = match path.as_deref() {
+        Some("-") | None if write => return usage("--write needs a file"),
+        p => p,
+    };
+
+    let bts = match read_input(file) {
+        Ok(bts) => bts,
+        Err(e) => {
+            eprintln!("fuko: {}: {}", file.unwrap_or("stdin"), e);
+            return 1
+        }
+    };
+
+    let out = match fuko::format_bytes(&bts) {
+        Ok(out) => out,
+        Err(d) => {
+            eprint!("{}", d);
+            return 1
+        }
+    };
+
+    match file {
+        Some(p) if write => {
+            if let Err(e) = fs::write(p, out) {
+                eprintln!("fuko: {}: {}", p, e);
+                return 1
+            }
+        }
+        _ => print!("{}", out),
+    }
+
+    0
+}
+
+fn read_input(path: Option<&str>) -> io::Result<Vec<u8>> {
+    match path {
+        None | Some("-") => {
+            let mut bts = vec!();
+            io::stdin().read_to_end(&mut bts)?;
+            Ok(bts)
+        }
+        Some(p) => fs::read(p),
+    }
+}
+
+fn usage(message: &str) -> i32 {
+    eprintln!("fuko: {}\n\n{}", message, USAGE);
+    2
+}
@@ -0,0 +1,152 @@
+// trades money between sources to get rid of transfers: when two sources both send to the
+// same two sinks, one can send more to the first sink and less to the second while the other
+// does the opposite, and the same goes around longer rings of sources and sinks. What every
+// source sends and every sink receives stays the same.
+
+use crate::{Book, Fraction, Graph, Or, Unary};
+
+impl Graph {
+    // trades away transfers smaller than `below`, smallest first, for as long as that's
+    // possible. Every trade gets rid of at least one transfer, so this ends.
+    pub(crate) fn consolidate(&mut self, book: &mut Book, below: Fraction) {
+        loop {
+            let mut small = vec!();
+            for tr in &book.transfers {
+                for (snk_id, amount) in &tr.to {
+                    if *amount < below {
+                        small.push((tr.from, *snk_id, amount.clone()));
+                    }
+                }
+            }
+            small.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+
+            if !small.iter().any(|(src_id, snk_id, _)| self.trade(book, *src_id, *snk_id)) {
+                break
+            }
+        }
+    }
+
+    // moves what src sends to snk over to another of its sinks, with other sources passing
+    // the difference around, returns whether a trade was found
+    fn trade(&mut self, book: &mut Book, src: usize, snk: usize) -> bool {
+        // the sources and sinks already on the way
+        let mut seen = (vec!(false; self.sources.len()), vec!(false; self.sinks.len()));
+        seen.0[src] = true;
+        seen.1[snk] = true;
+
+        let mut moves = vec!();
+        let y = match self.cycle(book, (src, snk), snk, book.amount(src, snk), &mut moves, &mut seen) {
+            Some(y) => y,
+            None => return false,
+        };
+
+        for (s, from, to) in moves {
+            *book.get_fraction_or_insert(s, from) -= y.clone();
+            *book.get_fraction_or_insert(s, to) += y.clone();
+            self.move_amount(s, from, to, y.clone());
+        }
+        book.remove_empty();
+        true
+    }
+
+    // looks for a way to get y more to `snk` from sources that already send to it, each of
+    // them sending as much less to another of its sinks, until it gets back to a sink the
+    // source of `first` sends to. `moves` collects who sends less to which sink and more to
+    // which. Returns how much can be traded, which always ends one of the transfers.
+    fn cycle(
+        &self,
+        book: &Book,
+        first: (usize, usize),
+        snk: usize,
+        y: Fraction,
+        moves: &mut Vec<(usize, usize, usize)>,
+        seen: &mut (Vec<bool>, Vec<bool>),
+    ) -> Option<Fraction> {
+        let (src, first_snk) = first;
+        if snk != first_snk && book.amount(src, snk) > Fraction::from(0) {
+            moves.push((src, first_snk, snk));
+            if moves.iter().all(|(s, from, to)| self.fits(*s, *from, *to, y.clone())) {
+                return Some(y)
+            }
+            moves.pop();
+        }
+
+        for src2 in 0..self.sources.len() {
+            if seen.0[src2] || book.amount(src2, snk) == Fraction::from(0) {
+                continue
+            }
+            seen.0[src2] = true;
+
+            for snk2 in 0..self.sinks.len() {
+                let other = book.amount(src2, snk2);
+                if seen.1[snk2] || other == Fraction::from(0) {
+                    continue
+                }
+                seen.1[snk2] = true;
+
+                moves.push((src2, snk2, snk));
+                let y2 = if other < y { other } else { y.clone() };
+                if let Some(y) = self.cycle(book, first, snk2, y2, moves, seen) {
+                    return Some(y)
+                }
+                moves.pop();
+            }
+        }
+
+        None
+    }
+
+    // whether src can send y less to `from` and y more to `to` without going over a cap or
+    // changing the split of an AND with weights
+    fn fits(&self, src: usize, from: usize, to: usize, y: Fraction) -> bool {
+        let or = &self.sources[src].to;
+        let (from_path, to_path) = match (self.path(or, from), self.path(or, to)) {
+            (Some(f), Some(t)) => (f, t),
+            _ => return false,
+        };
+        let mut moved = from_path.iter().filter(|u| !to_path.contains(u)).chain(to_path.iter().filter(|u| !from_path.contains(u)));
+        if moved.any(|u| self.unaries[*u].weighted) {
+            return false
+        }
+
+        to_path.iter()
+            .filter(|u| !from_path.contains(u))
+            .all(|u| self.unaries[*u].amount.clone() + y.clone() <= self.unaries[*u].capacity)
+    }
+
+    fn move_amount(&mut self, src: usize, from: usize, to: usize, y: Fraction) {
+        let or = &self.sources[src].to;
+        let (from_path, to_path) = (self.path(or, from).unwrap(), self.path(or, to).unwrap());
+        for u in from_path {
+            self.unaries[u].amount -= y.clone();
+        }
+        for u in to_path {
+            self.unaries[u].amount += y.clone();
+        }
+    }
+
+    // the expression nodes money goes through from `or` to a sink, if there's exactly one way
+    // there. Sinks that can be reached in several ways are left alone, it isn't known which
+    // of them the money took.
+    fn path(&self, or: &Or, snk: usize) -> Option<Vec<usize>> {
+        let mut paths = vec!();
+        self.paths(or, snk, &mut vec!(), &mut paths);
+        if paths.len() == 1 {
+            paths.pop()
+        } else {
+            None
+        }
+    }
+
+    fn paths(&self, or: &Or, snk: usize, prefix: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+        for ui in or.iter().flatten() {
+            prefix.push(*ui);
+            match &self.unaries[*ui].unary {
+                Unary::Val(i) if *i == snk => out.push(prefix.clone()),
+                Unary::Val(_) => (),
+                Unary::Expr(e) => self.paths(e, snk, prefix, out),
+            }
+            prefix.pop();
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// CSV exports of the transfer book and of the sinks, for spreadsheets and banking
+
+use crate::allocation::{format_cents, to_cents};
+use crate::{Allocation, Simulation, BASE_CURRENCY};
+
+fn field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn month(a: &Allocation) -> String {
+    a.date.map(|d| d.to_string()).unwrap_or_default()
+}
+
+fn transfers_csv(allocations: &[Allocation]) -> String {
+    let rows: Vec<_> = allocations.iter().flat_map(|a| a.transfers.iter().map(move |t| (a, t))).collect();
+    // amounts are in the base currency, paid is what the source pays in its own,
+    // net is what arrives once the fee is taken
+    let mut out = "source,sink,amount,currency,period,month,paid,paid_currency,fee,net,matching\n".to_owned();
+    for (a, t) in rows {
+        out += &format!(
+            "{},{},{},{},month,{},{},{},{},{},{}\n",
+            field(&t.source), field(&t.sink), format_cents(t.cents), BASE_CURRENCY, month(a),
+            format_cents(t.paid_cents), t.currency, format_cents(t.fee_cents), format_cents(t.net_cents()), t.matching,
+        );
+    }
+    out
+}
+
+fn sinks_csv(allocations: &[Allocation]) -> String {
+    // every value is rounded on its own and the shortfall follows from the rounded ones, so a
+    // row adds up. Sinks that only appear in flows have no need, those cells are left empty
+    let mut out = "sink,need,balance,received,shortfall,currency,period,month\n".to_owned();
+    for (a, s) in allocations.iter().flat_map(|a| a.sinks.iter().map(move |s| (a, s))) {
+        let unlimited = s.need.is_infinite();
+        out += &format!(
+            "{},{},{},{},{},{},{},{}\n",
+            field(&s.name),
+            if unlimited { String::new() } else { format_cents(to_cents(s.need.clone())) },
+            format_cents(to_cents(s.balance.clone())),
+            format_cents(s.received_cents),
+            if unlimited { String::new() } else { format_cents(s.shortfall_cents()) },
+            BASE_CURRENCY,
+            s.period.map(|p| p.to_string()).unwrap_or_else(|| "once".to_owned()),
+            month(a),
+        );
+    }
+    out
+}
+
+impl Allocation {
+    /// One row per transfer, amounts are per month in the reconciled cents of
+    /// [`TransferAllocation::cents`](crate::TransferAllocation::cents).
+    pub fn transfers_csv(&self) -> String {
+        transfers_csv(std::slice::from_ref(self))
+    }
+
+    /// One row per sink with its need, balance, what it received and what is still missing.
+    pub fn sinks_csv(&self) -> String {
+        sinks_csv(std::slice::from_ref(self))
+    }
+}
+
+impl Simulation {
+    /// The transfers of every simulated month.
+    pub fn transfers_csv(&self) -> String {
+        transfers_csv(&self.months)
+    }
+
+    /// The sinks of every simulated month.
+    pub fn sinks_csv(&self) -> String {
+        sinks_csv(&self.months)
+    }
+}
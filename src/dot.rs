@@ -0,0 +1,131 @@
+// renders the flow graph as Graphviz DOT, with what the solver sent along every edge
+
+use crate::allocation::{format_cents, to_cents};
+use crate::{Fraction, Graph, Or, Plan, Unary};
+
+impl Plan {
+    /// The flows of the month the plan is evaluated for as a Graphviz DOT digraph.
+    /// Sources, capped or nested expressions and sinks are nodes, edges are labeled
+    /// with what was sent along them and fallbacks after `||` are dashed.
+    pub fn to_dot(&self) -> String {
+        let date = self.start_date();
+        let mut graph = self.graph.clone();
+        graph.fill(date, &mut |_| ());
+
+        let mut out = "digraph fuko {\n    rankdir=LR;\n    node [fontname=\"sans-serif\"];\n    edge [fontname=\"sans-serif\"];\n\n".to_owned();
+
+        for (i, s) in graph.sources.iter().enumerate() {
+            if !s.matches.is_empty() {
+                let matches: Vec<String> = s.matches.iter().map(|m| format!("\\nmatches {}", escape(&m.label))).collect();
+                out += &format!("    source{} [shape=box, label=\"{}{}\"];\n", i, escape(&self.source_names[i]), matches.concat());
+                continue
+            }
+
+            let mut window = String::new();
+            if let Some(d) = s.from {
+                window += &format!("\\nfrom {}", d);
+            }
+            if let Some(d) = s.until {
+                window += &format!("\\nuntil {}", d);
+            }
+            if s.one_time(date) > Fraction::from(0) {
+                window += &format!("\\ngives {} once", cents(s.one_time(date)));
+            }
+            if s.if_funded {
+                window += "\\nonly if funded";
+            }
+            out += &format!(
+                "    source{} [shape=box, label=\"{}\\ncommits {}/month{}\\nunspent {}/month\"];\n",
+                i, escape(&self.source_names[i]), cents(s.committed(date)), window, cents(s.amount.clone()),
+            );
+        }
+
+        for (i, s) in graph.sinks.iter().enumerate() {
+            let need = if s.capacity.is_infinite() {
+                "no limit".to_owned()
+            } else if s.period.is_some() {
+                format!("needs {}/month", cents(s.capacity.clone()))
+            } else {
+                format!("needs {}", cents(s.capacity.clone()))
+            };
+            out += &format!(
+                "    sink{} [shape=ellipse, label=\"{}\\n{}\\nbalance {}\\nreceived {}\"];\n",
+                i, escape(&self.sink_names[i]), need, cents(s.balance.clone()), cents(s.add_amount.clone()),
+            );
+        }
+
+        for (i, u) in graph.unaries.iter().enumerate() {
+            if has_node(&graph, i) {
+                let cap = if u.capacity.is_infinite() {
+                    String::new()
+                } else {
+                    format!("\\ncap {}/month", cents(u.capacity.clone()))
+                };
+                out += &format!(
+                    "    unary{} [shape=diamond, label=\"{}{}\\nfilled {}\"];\n",
+                    i, escape(&u.label), cap, cents(u.amount.clone()),
+                );
+            }
+        }
+
+        out.push('\n');
+
+        for (i, s) in graph.sources.iter().enumerate() {
+            edges(&graph, &format!("source{}", i), &s.to, &mut out);
+            // what matching sources added goes straight to the sink
+            for m in &s.matches {
+                out += &format!("    source{} -> sink{} [label=\"{}\", style=dotted];\n", i, m.sink, cents(m.sent.clone()));
+            }
+        }
+        for (i, u) in graph.unaries.iter().enumerate() {
+            if !has_node(&graph, i) {
+                continue
+            }
+            match &u.unary {
+                Unary::Val(snk) => out += &edge(&format!("unary{}", i), &format!("sink{}", snk), cents(u.amount.clone()), false),
+                Unary::Expr(or) => edges(&graph, &format!("unary{}", i), or, &mut out),
+            }
+        }
+
+        out += "}\n";
+        out
+    }
+}
+
+// plain references to a sink are drawn as an edge straight to it, everything else gets its own node
+fn has_node(graph: &Graph, i: usize) -> bool {
+    let u = &graph.unaries[i];
+    matches!(u.unary, Unary::Expr(_)) || !u.capacity.is_infinite()
+}
+
+fn edges(graph: &Graph, from: &str, or: &Or, out: &mut String) {
+    for (n, and) in or.iter().enumerate() {
+        for ui in and {
+            let u = &graph.unaries[*ui];
+            let (to, label) = match u.unary {
+                // the weight would otherwise only show in the node's label
+                Unary::Val(snk) if !has_node(graph, *ui) && u.weight != Fraction::from(1) => {
+                    (format!("sink{}", snk), format!("{}\\nweight {}", cents(u.amount.clone()), u.weight))
+                }
+                Unary::Val(snk) if !has_node(graph, *ui) => (format!("sink{}", snk), cents(u.amount.clone())),
+                _ => (format!("unary{}", ui), cents(u.amount.clone())),
+            };
+            *out += &edge(from, &to, label, n > 0);
+        }
+    }
+}
+
+fn edge(from: &str, to: &str, label: String, fallback: bool) -> String {
+    format!(
+        "    {} -> {} [label=\"{}\"{}];\n",
+        from, to, label, if fallback { ", style=dashed" } else { "" },
+    )
+}
+
+fn cents(f: Fraction) -> String {
+    format_cents(to_cents(f))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
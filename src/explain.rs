@@ -0,0 +1,121 @@
+// narrates the solver's steps in plain language, for people checking why money went where it did
+
+use crate::allocation::{format_cents, to_cents};
+use crate::{Fraction, Plan, Step, StopReason};
+
+impl Plan {
+    /// Narrates every step the solver takes in the first `months` months, naming what
+    /// each source sends where and the cap, sink or source that ended the step.
+    pub fn explain(&self, months: u32) -> String {
+        let mut out = String::new();
+        let mut current = None;
+        let mut n = 0;
+        let start = self.start_date();
+
+        self.run(months, &mut |month, step| {
+            if current != Some(month) {
+                if current.is_some() {
+                    out.push('\n');
+                }
+                current = Some(month);
+                n = 0;
+
+                let mut date = start;
+                for _ in 0..month {
+                    date = date.map(|d| d.next());
+                }
+                match date {
+                    Some(d) => out += &format!("month {}:\n", d),
+                    None if months > 1 => out += &format!("month {}:\n", month+1),
+                    None => (),
+                }
+            }
+
+            n += 1;
+            out += &format!("step {}: {}\n", n, self.narrate(step));
+        });
+
+        if current.is_none() {
+            out += "nothing to send, no source has a flow to a sink that isn't full\n";
+        }
+
+        out
+    }
+
+    fn narrate(&self, step: &Step) -> String {
+        let amount = format_cents(to_cents(step.amount.clone()));
+
+        let names: Vec<&str> = step.src_to_snks.iter().map(|(i, _)| self.source_names[*i].as_str()).collect();
+        let dests: Vec<String> = step.src_to_snks.iter().map(|(_, shares)| self.destination(step.amount.clone(), shares)).collect();
+
+        let sends = if names.len() == 1 {
+            format!("{} sends {}", names[0], amount)
+        } else {
+            format!("{} send {} each", list(&names), amount)
+        };
+
+        let sends = if dests.iter().all(|d| *d == dests[0]) {
+            if dests[0].starts_with("to ") {
+                format!("{} {}", sends, dests[0])
+            } else {
+                format!("{}, {},", sends, dests[0])
+            }
+        } else {
+            let each: Vec<String> = names.iter().zip(&dests).map(|(n, d)| format!("{} {}", n, d)).collect();
+            format!("{}, {},", sends, each.join("; "))
+        };
+
+        let sends = if step.matches.is_empty() {
+            sends
+        } else {
+            let adds: Vec<String> = step.matches.iter().map(|(i, j, rate)| format!(
+                "{} adds {} to {}",
+                self.source_names[*i], format_cents(to_cents(step.amount.clone() * rate.clone())),
+                self.sink_names[self.graph.sources[*i].matches[*j].sink],
+            )).collect();
+            match sends.strip_suffix(',') {
+                Some(s) => format!("{}, while {},", s, list(&adds)),
+                None => format!("{} while {}", sends, list(&adds)),
+            }
+        };
+
+        format!("{} until {}", sends, self.stop(&step.stop_reason))
+    }
+
+    // where one source's step amount goes, e.g. "to rent" or "10.00 to rent and 5.00 to food"
+    fn destination(&self, amount: Fraction, shares: &[(usize, Fraction)]) -> String {
+        if shares.len() == 1 {
+            return format!("to {}", self.sink_names[shares[0].0])
+        }
+
+        let parts: Vec<String> = shares.iter().map(|(i, share)| {
+            format!("{} to {}", format_cents(to_cents(amount.clone() * share.clone())), self.sink_names[*i])
+        }).collect();
+        list(&parts)
+    }
+
+    fn stop(&self, reason: &StopReason) -> String {
+        match reason {
+            StopReason::SourceDepleted(i) => format!("{} has nothing left", self.source_names[*i]),
+            StopReason::SinkFull(i) => format!("{} is full", self.sink_names[*i]),
+            StopReason::NodeFull(i) => format!("the cap of {} is reached", self.graph.unaries[*i].label),
+            StopReason::MatchFull(i, j) => format!(
+                "{} reaches the cap of matching {}",
+                self.source_names[*i], self.graph.sources[*i].matches[*j].label,
+            ),
+        }
+    }
+}
+
+// "a", "a and b", "a, b and c"
+fn list<S: AsRef<str>>(items: &[S]) -> String {
+    match items {
+        [] => String::new(),
+        [one] => one.as_ref().to_owned(),
+        [init @ .., last] => format!(
+            "{} and {}",
+            init.iter().map(|s| s.as_ref()).collect::<Vec<_>>().join(", "),
+            last.as_ref(),
+        ),
+    }
+}
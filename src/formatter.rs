@@ -0,0 +1,313 @@
+// writes a fuko file back in canonical form: statements grouped by kind, balances sorted
+// by date, one spelling for amounts and operators, and comments kept with their statements
+
+use pest::iterators::Pair;
+use pest::Parser;
+
+use crate::{Diagnostics, FukoParser, ParseError, Rule};
+
+// the order groups of statements are written in
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    // rates, fees and how transfers are made
+    Setting,
+    Need,
+    Commitment,
+    Balance,
+    Flow,
+    Simulation,
+}
+
+struct Statement {
+    kind: Kind,
+    // balances are sorted by (year, month), everything else keeps its order
+    date: (u32, u32),
+    text: String,
+    // comment lines right above the statement and the one at the end of its line
+    above: Vec<String>,
+    after: Option<String>,
+}
+
+/// Formats a fuko file canonically. Only syntax errors stop it, the plan itself
+/// doesn't have to make sense. Formatting the result again doesn't change it.
+pub fn format(input: &str) -> Result<String, Diagnostics> {
+    let file = match FukoParser::parse(Rule::file, input) {
+        Ok(mut f) => f.next().unwrap(),
+        Err(r) => {
+            let mut diags = Diagnostics{ items: vec!() };
+            diags.error(ParseError::Rule(Box::new(r)));
+            return Err(diags)
+        }
+    };
+
+    let mut header = vec!();
+    let mut statements: Vec<Statement> = vec!();
+    let mut pending: Vec<String> = vec!();
+    let mut last_line = 0;
+
+    for pair in file.into_inner() {
+        let (line, _) = pair.as_span().start_pos().line_col();
+
+        match pair.as_rule() {
+            Rule::comment => {
+                let text = pair.as_str().trim_end().to_owned();
+                if let Some(s) = statements.last_mut().filter(|_| line == last_line) {
+                    s.after = Some(text);
+                } else {
+                    // a blank line before the first statement ends the file's header comment
+                    if statements.is_empty() && !pending.is_empty() && line > last_line + 1 {
+                        header.append(&mut pending);
+                    }
+                    pending.push(text);
+                }
+            }
+            Rule::EOI => (),
+            _ => {
+                if statements.is_empty() && !pending.is_empty() && line > last_line + 1 {
+                    header.append(&mut pending);
+                }
+                let (kind, date) = kind(&pair);
+                statements.push(Statement{
+                    kind,
+                    date,
+                    text: statement(pair),
+                    above: std::mem::take(&mut pending),
+                    after: None,
+                });
+            }
+        }
+
+        last_line = line;
+    }
+
+    statements.sort_by_key(|s| (s.kind, if s.kind == Kind::Balance { s.date } else { (0, 0) }));
+
+    let mut blocks: Vec<String> = vec!();
+    if !header.is_empty() {
+        blocks.push(header.join("\n") + "\n");
+    }
+
+    let mut block = String::new();
+    for (i, s) in statements.iter().enumerate() {
+        if i > 0 && statements[i-1].kind != s.kind {
+            blocks.push(std::mem::take(&mut block));
+        }
+        for c in &s.above {
+            block += c;
+            block.push('\n');
+        }
+        block += &s.text;
+        if let Some(c) = &s.after {
+            block += " ";
+            block += c;
+        }
+        block.push('\n');
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+
+    // comments at the end that belong to no statement
+    if !pending.is_empty() {
+        blocks.push(pending.join("\n") + "\n");
+    }
+
+    Ok(blocks.join("\n"))
+}
+
+/// Like [`format`], for input that may not be valid UTF-8.
+pub fn format_bytes(bts: &[u8]) -> Result<String, Diagnostics> {
+    match std::str::from_utf8(bts) {
+        Ok(s) => format(s),
+        Err(e) => {
+            let mut diags = Diagnostics{ items: vec!() };
+            diags.error(ParseError::InvalidUtf8(e));
+            Err(diags)
+        }
+    }
+}
+
+fn kind(pair: &Pair<Rule>) -> (Kind, (u32, u32)) {
+    match pair.as_rule() {
+        Rule::verb_statement => match pair.clone().into_inner().nth(1).unwrap().as_rule() {
+            Rule::needs => (Kind::Need, (0, 0)),
+            _ => (Kind::Commitment, (0, 0)),
+        },
+        Rule::date_verb_statement => {
+            let date = pair.clone().into_inner().next().unwrap();
+            let mut parts = date.as_str().split('-').map(|p| p.parse::<u32>().unwrap_or(u32::MAX));
+            (Kind::Balance, (parts.next().unwrap(), parts.next().unwrap()))
+        }
+        Rule::match_statement => (Kind::Commitment, (0, 0)),
+        Rule::flow_statement => (Kind::Flow, (0, 0)),
+        Rule::simulate_statement => (Kind::Simulation, (0, 0)),
+        Rule::rate_statement | Rule::fee_statement | Rule::minimum_statement | Rule::minimise_statement => {
+            (Kind::Setting, (0, 0))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn statement(pair: Pair<Rule>) -> String {
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner();
+
+    match rule {
+        Rule::verb_statement => {
+            let ident = inner.next().unwrap().as_str();
+            let verb = inner.next().unwrap();
+            let mut res = format!("{} {} {}", ident, verb.as_str(), currency(inner.next().unwrap()));
+            for p in inner {
+                match p.as_rule() {
+                    Rule::deadline => res += &format!(" by {}", date(p.into_inner().next().unwrap().as_str())),
+                    Rule::ask_for_balance => res += ", ask for balance",
+                    Rule::starts => res += &format!(" from {}", date(p.into_inner().next().unwrap().as_str())),
+                    Rule::ends => res += &format!(" until {}", date(p.into_inner().next().unwrap().as_str())),
+                    Rule::on => res += &format!(" on {}", date(p.into_inner().next().unwrap().as_str())),
+                    Rule::carry_over => res += ", carry over",
+                    _ => unreachable!(),
+                }
+            }
+            res
+        }
+        Rule::date_verb_statement => {
+            let date = date(inner.next().unwrap().as_str());
+            let ident = inner.next().unwrap().as_str();
+            format!("{} {} had {}", date, ident, currency(inner.next().unwrap()))
+        }
+        Rule::match_statement => {
+            let ident = inner.next().unwrap().as_str();
+            let sink = inner.next().unwrap().as_str();
+            let ratio: Vec<String> = inner.next().unwrap().into_inner().map(|n| number(n.as_str(), 0)).collect();
+            let mut res = format!("{} matches {} {}", ident, sink, ratio.join(":"));
+            if let Some(cap) = inner.next() {
+                res += &format!(" up to {}", currency(cap.into_inner().next().unwrap()));
+            }
+            res
+        }
+        Rule::flow_statement => {
+            let ident = inner.next().unwrap().as_str();
+            let res = format!("{} -> {}", ident, expr(inner.next().unwrap()));
+            match inner.next() {
+                Some(_) => res + " if funded",
+                None => res,
+            }
+        }
+        Rule::simulate_statement => {
+            let n = inner.next().unwrap().as_str();
+            format!("simulate {} {}", n, if n == "1" { "month" } else { "months" })
+        }
+        Rule::rate_statement => {
+            let code = inner.next().unwrap().as_str();
+            let rate = number(inner.next().unwrap().as_str(), 0);
+            format!("rate {} = {} {}", code, rate, inner.next().unwrap().as_str())
+        }
+        Rule::fee_statement => {
+            let parts: Vec<String> = inner.map(|p| match p.as_rule() {
+                Rule::percentage => format!("{}%", number(p.into_inner().next().unwrap().as_str(), 0)),
+                _ => currency(p),
+            }).collect();
+            format!("fee {}", parts.join(" + "))
+        }
+        Rule::minimum_statement => format!("minimum transfer {}", currency(inner.next().unwrap())),
+        Rule::minimise_statement => "minimise transfers".to_owned(),
+        _ => unreachable!(),
+    }
+}
+
+fn expr(pair: Pair<Rule>) -> String {
+    let or = pair.into_inner().next().unwrap();
+    let ands: Vec<String> = or.into_inner().map(|and| {
+        let unaries: Vec<String> = and.into_inner().map(unary).collect();
+        unaries.join(" && ")
+    }).collect();
+    ands.join(" || ")
+}
+
+fn unary(pair: Pair<Rule>) -> String {
+    let mut inner = pair.into_inner();
+    let target = inner.next().unwrap();
+    let mut res = match target.as_rule() {
+        Rule::identifier => target.as_str().to_owned(),
+        _ => format!("({})", expr(target)),
+    };
+
+    for p in inner {
+        match p.as_rule() {
+            Rule::periodic_currency => res += &format!("({})", currency(p)),
+            Rule::weight => {
+                let percent = p.as_str().trim_end().ends_with('%');
+                let n = number(p.into_inner().next().unwrap().as_str(), 0);
+                if percent {
+                    res += &format!(" {}%", n);
+                } else {
+                    res += &format!("*{}", n);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    res
+}
+
+// "€1200/year", "$12.50" or "300 CHF/month", currencies with a symbol are written with it
+fn currency(pair: Pair<Rule>) -> String {
+    let pair = match pair.as_rule() {
+        Rule::currency => pair.into_inner().next().unwrap(),
+        _ => pair,
+    };
+
+    let mut symbol = None;
+    let mut code = None;
+    let mut amount = String::new();
+    let mut period = String::new();
+    for p in pair.into_inner() {
+        match p.as_rule() {
+            Rule::symbol => symbol = Some(p.as_str().to_owned()),
+            Rule::code => code = Some(p.as_str().to_owned()),
+            Rule::number => amount = number(p.as_str(), 2),
+            Rule::period => period = format!("/{}", p.as_str()),
+            _ => unreachable!(),
+        }
+    }
+
+    let from_symbol = symbol.as_deref().map(|s| match s {
+        "$" => "USD",
+        "£" => "GBP",
+        _ => "EUR",
+    });
+    match (from_symbol, code.as_deref()) {
+        // a symbol that doesn't match the code is left for the parser to report
+        (Some(s), Some(c)) if s != c => format!("{}{} {}{}", symbol.unwrap(), amount, c, period),
+        (_, Some("USD")) | (Some("USD"), None) => format!("${}{}", amount, period),
+        (_, Some("GBP")) | (Some("GBP"), None) => format!("£{}{}", amount, period),
+        (_, Some(c)) if c != "EUR" => format!("{} {}{}", amount, c, period),
+        _ => format!("€{}{}", amount, period),
+    }
+}
+
+// drops trailing zeros but keeps at least `min_decimals` once there is a fraction, so
+// 12.5 is written 12.50 for money, and 12.00 is written 12
+fn number(s: &str, min_decimals: usize) -> String {
+    let (int, decimals) = match s.split_once('.') {
+        Some((i, d)) => (i, d.trim_end_matches('0')),
+        None => (s, ""),
+    };
+    let int = if int == "-0" && decimals.is_empty() { "0" } else { int };
+
+    if decimals.is_empty() {
+        int.to_owned()
+    } else {
+        format!("{}.{:0<2$}", int, decimals, min_decimals)
+    }
+}
+
+// zero pads dates like 2022-5, dates that don't make sense are left for the parser to report
+fn date(s: &str) -> String {
+    let mut parts = s.split('-').map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(y)), Some(Ok(m))) => format!("{:04}-{:02}", y, m),
+        _ => s.to_owned(),
+    }
+}
@@ -0,0 +1,165 @@
+// JSON output of allocations, small enough to not pull in serde
+
+use std::fmt::Write;
+
+use crate::allocation::{format_cents, to_cents};
+use crate::{Allocation, Date, Fraction, Period, Simulation};
+
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn write(&self, out: &mut String, indent: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Number(n) => out.push_str(n),
+            Json::String(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, indent + 1);
+                    item.write(out, indent + 1);
+                }
+                newline(out, indent);
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, indent + 1);
+                    write!(out, "\"{}\": ", key).unwrap();
+                    value.write(out, indent + 1);
+                }
+                newline(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn to_string(json: Json) -> String {
+    let mut out = String::new();
+    json.write(&mut out, 0);
+    out.push('\n');
+    out
+}
+
+// exact fractions like "800/3" next to the amount rounded to cents, null for unlimited amounts
+fn amount(f: Fraction) -> Json {
+    if f.is_infinite() {
+        return Json::Null
+    }
+    reconciled(f.clone(), to_cents(f))
+}
+
+// an exact amount next to the cents it was rounded to when reconciling the book
+fn reconciled(f: Fraction, cents: i64) -> Json {
+    Json::Object(vec!(
+        ("exact", Json::String(f.to_string())),
+        ("rounded", Json::Number(format_cents(cents))),
+    ))
+}
+
+fn date(d: Option<Date>) -> Json {
+    match d {
+        Some(d) => Json::String(d.to_string()),
+        None => Json::Null,
+    }
+}
+
+fn period(p: Option<Period>) -> Json {
+    match p {
+        Some(p) => Json::String(p.to_string()),
+        None => Json::Null,
+    }
+}
+
+fn allocation(a: &Allocation) -> Json {
+    Json::Object(vec!(
+        ("month", date(a.date)),
+        ("verdict", Json::String(a.verdict().as_str().to_owned())),
+        ("sources", Json::Array(a.sources.iter().map(|s| Json::Object(vec!(
+            ("name", Json::String(s.name.clone())),
+            ("period", period(Some(s.period))),
+            ("currency", Json::String(s.currency.clone())),
+            ("committed", amount(s.committed.clone())),
+            ("one_time", amount(s.one_time.clone())),
+            ("from", date(s.from)),
+            ("until", date(s.until)),
+            ("unspent", reconciled(s.unspent.clone(), s.unspent_cents)),
+            ("carried", amount(s.carried.clone())),
+            ("withheld", Json::Array(s.withheld.iter().map(|k| Json::String(k.clone())).collect())),
+            ("too_small", Json::Array(s.too_small.iter().map(|k| Json::String(k.clone())).collect())),
+        ))).collect())),
+        ("sinks", Json::Array(a.sinks.iter().map(|s| Json::Object(vec!(
+            ("name", Json::String(s.name.clone())),
+            ("period", period(s.period)),
+            ("need", amount(s.need.clone())),
+            ("balance", amount(s.balance.clone())),
+            ("received", reconciled(s.received.clone(), s.received_cents)),
+            ("remaining", amount(s.remaining())),
+            ("leftover_cents", Json::Number(s.leftover_cents.to_string())),
+            ("date", date(s.date)),
+            ("deadline", date(s.deadline)),
+        ))).collect())),
+        ("transfers", Json::Array(a.transfers.iter().map(|t| Json::Object(vec!(
+            ("source", Json::String(t.source.clone())),
+            ("sink", Json::String(t.sink.clone())),
+            ("period", period(Some(t.period))),
+            ("amount", reconciled(t.amount.clone(), t.cents)),
+            ("fee", reconciled(t.fee.clone(), t.fee_cents)),
+            ("net", reconciled(t.net(), t.net_cents())),
+            ("matching", Json::Bool(t.matching)),
+            ("currency", Json::String(t.currency.clone())),
+            ("paid", reconciled(t.paid.clone(), t.paid_cents)),
+        ))).collect())),
+    ))
+}
+
+impl Allocation {
+    /// The allocation as JSON, amounts are per month.
+    pub fn to_json(&self) -> String {
+        to_string(allocation(self))
+    }
+}
+
+impl Simulation {
+    /// The allocation of every simulated month as JSON.
+    pub fn to_json(&self) -> String {
+        to_string(Json::Object(vec!(
+            ("months", Json::Array(self.months.iter().map(allocation).collect())),
+        )))
+    }
+}
@@ -3,275 +3,767 @@ extern crate pest;
 extern crate pest_derive;
 
 use pest::Parser;
-use pest::error::Error;
-use fraction::Fraction;
+use pest::error::{Error, ErrorVariant};
+use pest::Span;
+use std::fmt;
+use fraction::BigUint;
+
+/// Exact amounts. The integers behind them grow as needed, the products the solver builds
+/// from a few decimals quickly outgrow 64 bits.
+pub type Fraction = fraction::BigFraction;
+
+mod allocation;
+mod consolidate;
+mod csv;
+mod dot;
+mod explain;
+mod formatter;
+mod json;
+mod rounding;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use formatter::{format, format_bytes};
+pub use allocation::{Allocation, SinkAllocation, Simulation, SourceAllocation, TransferAllocation, Verdict};
+
+const MAX_DIGITS: usize = 12;
+const MAX_DECIMALS: usize = 4;
+// exchange rates are quoted with more places than amounts
+const MAX_RATE_DECIMALS: usize = 8;
+/// The most months a plan can be simulated for.
+pub const MAX_MONTHS: u32 = 1200;
+/// The currency amounts are converted into before solving.
+pub const BASE_CURRENCY: &str = "EUR";
+// how often sources get to make up for flat fees before the fees are left as they are
+const MAX_SETTLE_ROUNDS: u32 = 10;
 
 #[derive(Parser)]
 #[grammar = "fuko.pest"]
 struct FukoParser;
 
-fn read_all() -> Vec<u8> {
-    let buf: [u8; 1024] = [0; 1024];
-    let mut res: Vec<u8> = [].to_vec();
+#[derive(Debug, Clone)]
+struct Source {
+    rate: Fraction,
+    period: Period,
+    // the first and last month the commitment holds for, both included
+    from: Option<Date>,
+    until: Option<Date>,
+    gifts: Vec<Gift>,
+    // what's left of carried over gifts from earlier months
+    carry: Fraction,
+    // the currency the source pays in and what one unit of it is worth in the base currency
+    currency: Option<(String, Fraction)>,
+    amount: Fraction,
+    to: Or,
+    // only pay sinks that end up fully funded
+    if_funded: bool,
+    // sinks a conditional source doesn't pay this month because they would end up short
+    withheld: Vec<usize>,
+    // sinks the source doesn't pay this month because it would send them less than the minimum
+    too_small: Vec<usize>,
+    // sources that match what others give to sinks, instead of committing anything themselves
+    matches: Vec<Match>,
+    declared_at: Option<(usize, usize)>,
+    flow_at: Option<(usize, usize)>,
+}
 
-    loop {
-        let n;
-        unsafe {
-            n = read(&buf[0], buf.len());
+// a pledge to add `ratio` times what other sources send to a sink, up to a cap per month
+#[derive(Debug, Clone)]
+struct Match {
+    sink: usize,
+    ratio: Fraction,
+    cap: Fraction,
+    // what was added so far this month
+    sent: Fraction,
+    // the pledge as written after "matches", to name it when explaining
+    label: String,
+    at: (usize, usize),
+}
+
+// a one-time contribution, available in a single month
+#[derive(Debug, Clone)]
+struct Gift {
+    amount: Fraction,
+    // the first month the plan is evaluated for when no date was given
+    date: Option<Date>,
+    carry_over: bool,
+    at: (usize, usize),
+}
+
+impl Source {
+    fn currency(&self) -> (&str, Fraction) {
+        match &self.currency {
+            Some((code, rate)) => (code, rate.clone()),
+            None => (BASE_CURRENCY, Fraction::from(1)),
+        }
+    }
+
+    // the source's currency is the one it first declared an amount in
+    fn set_currency(&mut self, ident: &str, span: Span, currency: (String, Fraction)) -> Result<(), ParseError> {
+        match &self.currency {
+            Some((code, _)) if *code != currency.0 => Err(ParseError::at(span, format!(
+                "{} already pays in {}, a sender can only pay in one currency",
+                ident, code,
+            ))),
+            _ => {
+                self.currency = Some(currency);
+                Ok(())
+            }
         }
+    }
 
-        if n == 0 {
-            return res
+    // what the source commits in the given month, nothing outside of its window
+    fn committed(&self, date: Option<Date>) -> Fraction {
+        let started = match (self.from, date) {
+            (Some(from), Some(d)) => !from.after(&d),
+            _ => true,
+        };
+        let ended = match (self.until, date) {
+            (Some(until), Some(d)) => d.after(&until),
+            _ => false,
+        };
+        if started && !ended {
+            self.rate.clone()
+        } else {
+            Fraction::from(0)
         }
+    }
 
-        res.extend_from_slice(&buf[0 .. n]);
+    // gifts due in the given month and what was carried over into it
+    fn one_time(&self, date: Option<Date>) -> Fraction {
+        self.gifts.iter().filter(|g| g.date == date).fold(self.carry.clone(), |acc, g| acc + g.amount.clone())
     }
-}
 
-fn write_bts(bts: &[u8]) {
-    unsafe {
-        write(&bts[0], bts.len());
+    // what's left of the source at the end of the month that carries over into the next,
+    // the periodic commitment is spent first so gifts last as long as possible
+    fn carried(&self, date: Option<Date>) -> Fraction {
+        let carryable = self.gifts.iter()
+            .filter(|g| g.date == date && g.carry_over)
+            .fold(self.carry.clone(), |acc, g| acc + g.amount.clone());
+        if self.amount < carryable {
+            self.amount.clone()
+        } else {
+            carryable
+        }
     }
 }
 
-fn write_err_bts(bts: &[u8]) {
-    unsafe {
-        write_err(&bts[0], bts.len());
+/// How often an amount recurs. Amounts are normalized to a month when parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+impl Period {
+    fn from(s: &str) -> Period {
+        match s {
+            "day" => Period::Day,
+            "week" => Period::Week,
+            "month" => Period::Month,
+            "quarter" => Period::Quarter,
+            "year" => Period::Year,
+            _ => unreachable!(),
+        }
+    }
+
+    fn per_year(&self) -> u32 {
+        match self {
+            Period::Day => 365,
+            Period::Week => 52,
+            Period::Month => 12,
+            Period::Quarter => 4,
+            Period::Year => 1,
+        }
+    }
+
+    // the solver works in months, everything else is converted on the way in and out
+    fn per_month(self, amount: Fraction) -> Fraction {
+        amount * Fraction::from(self.per_year()) / Fraction::from(12)
+    }
+
+    /// Restates a monthly amount in this period.
+    pub fn per_period(self, amount: Fraction) -> Fraction {
+        amount * Fraction::from(12) / Fraction::from(self.per_year())
     }
 }
 
-#[derive(Debug)]
-struct Source {
-    amount: Fraction,
-    to: Or,
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::Quarter => "quarter",
+            Period::Year => "year",
+        })
+    }
 }
 
-#[derive(Debug)]
-struct Date {
+const MIN_YEAR: u32 = 1900;
+const MAX_YEAR: u32 = 2999;
+
+/// A month of a year, as in `2022-05`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Date {
     year: u32,
     month: u32,
 }
 
 impl Date {
-    fn from(s: &str) -> Date {
-        let mut split = s.split("-");
+    fn parse(pair: Pair<Rule>) -> Result<Date, ParseError> {
+        let s = pair.as_str();
+        let invalid = |message: &str| ParseError::InvalidDate(located(pair.as_span(), format!("{} {}", s, message)));
 
-        Date {
-            year: split.next().unwrap().parse::<u32>().unwrap(),
-            month: split.next().unwrap().parse::<u32>().unwrap(),
+        let mut split = s.split('-');
+        let year = split.next().unwrap().parse::<u32>().map_err(|_| invalid("has an invalid year"))?;
+        let month = split.next().unwrap().parse::<u32>().map_err(|_| invalid("has an invalid month"))?;
+
+        if !(MIN_YEAR..=MAX_YEAR).contains(&year) {
+            return Err(invalid(&format!("is outside of years {} to {}", MIN_YEAR, MAX_YEAR)))
+        }
+        if !(1..=12).contains(&month) {
+            return Err(invalid("is not a valid month"))
         }
+
+        Ok(Date { year, month })
+    }
+
+    pub fn year(&self) -> u32 {
+        self.year
+    }
+
+    pub fn month(&self) -> u32 {
+        self.month
     }
 
     fn after(&self, o: &Date) -> bool {
         self.year > o.year || (self.year == o.year && self.month > o.month)
     }
 
-    fn to_string(&self) -> String {
-        format!("{}-{:0>2}",self.year, self.month)
+    // whole months from self to o, negative when o is earlier
+    fn months_until(&self, o: &Date) -> i64 {
+        (o.year as i64 - self.year as i64) * 12 + o.month as i64 - self.month as i64
+    }
+
+    fn next(&self) -> Date {
+        if self.month == 12 {
+            Date { year: self.year + 1, month: 1 }
+        } else {
+            Date { year: self.year, month: self.month + 1 }
+        }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{:0>2}", self.year, self.month)
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Sink {
     capacity: Fraction,
+    period: Option<Period>,
     balance: Fraction,
     add_amount: Fraction,
     date: Option<Date>,
+    // the month a one-off need should be funded by
+    deadline: Option<Date>,
     ask_for_balance: bool,
+    // byte ranges of the needs statement, the first flow mentioning the sink and
+    // its latest balance, for pointing diagnostics at them
+    declared_at: Option<(usize, usize)>,
+    referenced_at: Option<(usize, usize)>,
+    balance_at: Option<(usize, usize)>,
 }
 
 impl Sink {
     fn amount(&self) -> Fraction {
-        self.balance + self.add_amount
+        self.balance.clone() + self.add_amount.clone()
+    }
+
+    // periodic needs are spent every month and only the surplus carries over,
+    // one-off needs keep accumulating towards their capacity
+    fn roll_over(&mut self) {
+        let amount = self.amount();
+        self.balance = match self.period {
+            Some(_) if amount > self.capacity => amount - self.capacity.clone(),
+            Some(_) => Fraction::from(0),
+            None => amount,
+        };
+        self.add_amount = Fraction::from(0);
+    }
+
+    // what a need with a deadline still has to receive this month to be funded in time,
+    // everything that's missing once the deadline has passed
+    fn required(&self, date: Date) -> Option<Fraction> {
+        let deadline = self.deadline?;
+        let remaining = self.capacity.clone() - self.amount();
+        if remaining <= Fraction::from(0) {
+            return None
+        }
+        let months_left = date.months_until(&deadline).max(0) + 1;
+        Some(remaining / Fraction::from(months_left as u64))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Graph {
     sources: Vec<Source>,
     sinks: Vec<Sink>,
     unaries: Vec<UnaryCap>,
+    fee: Fee,
+    // transfers smaller than this are traded away between sources where possible, and not
+    // made at all otherwise
+    minimum: Option<Fraction>,
+    // trade away as many transfers as possible, not only the small ones
+    fewest_transfers: bool,
+    // while set, needs with a deadline that are short of what they need this month go
+    // ahead of the other sinks in their AND
+    deadlines_first: bool,
 }
 
-impl Graph {
-    fn next_sinks(&self, or: &Or, amount_to_send: Fraction) -> (Vec<usize>, Vec<(usize, usize)>, Fraction) {
-        let mut sinks = vec!();
-        let mut unaries = vec!();
-        let mut limited_amount = Fraction::infinity();
+// what a bank charges per transfer, taken from what the sink receives
+#[derive(Debug, Clone)]
+struct Fee {
+    flat: Fraction,
+    // a fraction of the transfer, 1% is 1/100
+    rate: Fraction,
+    declared: bool,
+}
+
+impl Fee {
+    // the whole fee on a transfer, never more than the transfer itself
+    fn of(&self, gross: Fraction) -> Fraction {
+        let fee = self.flat.clone() + gross.clone() * self.rate.clone();
+        if fee > gross {
+            gross
+        } else {
+            fee
+        }
+    }
+}
 
+impl Graph {
+    // returns the sinks and expression nodes the next bit of money from `or` goes to,
+    // along with the share of that money each of them receives. Sinks the source doesn't pay
+    // count as full.
+    fn next_sinks(&self, src: &Source, or: &Or) -> (Shares, Shares) {
         for and in or {
-            // let and_to_send = amount_to_send / Fraction::from(and.len());
+            let mut open = vec!();
+            let mut total_weight = Fraction::from(0);
+            let urgent_only = self.deadlines_first && and.iter().any(|ui| self.urgent(src, *ui));
 
             for ui in and {
+                if urgent_only && !self.urgent(src, *ui) {
+                    continue
+                }
                 let unary = &self.unaries[*ui];
                 if unary.amount >= unary.capacity {
                     // this expression node is already full, skip
                     continue
                 }
 
-                // let mut unary_to_send = and_to_send;
-                let mut unary_to_send = amount_to_send;
-                if unary.capacity - unary.amount < unary_to_send {
-                    // expression node capacity limits step amount, modify returned amount
-                    unary_to_send = unary.capacity - unary.amount;
-                    if unary_to_send < limited_amount {
-                        limited_amount = unary_to_send;
-                        // write_err_bts(format!("DEBUG1 set limited_amount {} {} {}\n", limited_amount, unary.capacity, unary.amount).as_bytes());
-                    }
-                };
-                
-                let utpl = match &unary.unary {
+                let (sinks, unaries) = match &unary.unary {
                     Unary::Val(i) => {
-                        if self.sinks[*i].capacity <= self.sinks[*i].amount() {
+                        if self.sinks[*i].capacity <= self.sinks[*i].amount() || src.withheld.contains(i) || src.too_small.contains(i) {
                             // sink is full, move on
                             continue
                         }
 
-                        sinks.push(*i);
-
-                        (*ui, 1)
+                        (vec!((*i, Fraction::from(1))), vec!())
                     }
                     Unary::Expr(e) => {
-                        
-                        let (mut more_sinks, mut more_unaries, new_limited_amount) = self.next_sinks(e, unary_to_send);
-                        if more_sinks.len() == 0 {
+                        let (sinks, unaries) = self.next_sinks(src, e);
+                        if sinks.is_empty() {
                             continue
                         }
 
-                        
-                        // let new_limited_amount = a;
-                        if new_limited_amount < limited_amount {
-                            // capacity reached somewhere downstream, modify returned amount
-                            limited_amount = new_limited_amount;
-                            // write_err_bts(format!("DEBUG2 set limited_amount {}\n", limited_amount).as_bytes());
-                        }
-
-                        // write_err_bts(format!("DEBUG more_sinks {:?} {} {}\n", more_sinks, *ui, more_sinks.len()).as_bytes());
-                        let utpl = (*ui, more_sinks.len());
-                        unaries.append(&mut more_unaries);
-                        sinks.append(&mut more_sinks);
-                        // unaries.push((*ui, more_sinks.len()));
-                        // write_err_bts(format!("DEBUG2\n").as_bytes());
-                        
-                        utpl
+                        (sinks, unaries)
                     }
                 };
 
-                if unary.capacity < Fraction::infinity() {
-                    unaries.push(utpl);
-                }
+                total_weight += unary.weight.clone();
+                open.push((*ui, unary.weight.clone(), sinks, unaries));
             }
 
-            if sinks.len() > 0 {
-                break
+            if open.is_empty() {
+                continue
+            }
+
+            // split between the nodes that aren't full yet, so the remainder of a full
+            // node gets redistributed according to the weights of the rest
+            let mut sinks = vec!();
+            let mut unaries = vec!();
+            for (ui, weight, more_sinks, more_unaries) in open {
+                let share = weight / total_weight.clone();
+                unaries.push((ui, share.clone()));
+                for (i, f) in more_sinks {
+                    sinks.push((i, f * share.clone()));
+                }
+                for (i, f) in more_unaries {
+                    unaries.push((i, f * share.clone()));
+                }
             }
+
+            return (sinks, unaries)
         };
 
-        // let n = Fraction::from(sinks.len());
+        (vec!(), vec!())
+    }
+
+    // a sink money going to `and` can end up in without limit, with no need and no cap on the
+    // way, so nothing after it is ever reached
+    fn endless_sink(&self, and: &[usize]) -> Option<usize> {
+        for ui in and {
+            let unary = &self.unaries[*ui];
+            if unary.capacity != Fraction::infinity() {
+                continue
+            }
+            let sink = match &unary.unary {
+                Unary::Val(i) if self.sinks[*i].capacity == Fraction::infinity() => Some(*i),
+                Unary::Val(_) => None,
+                Unary::Expr(e) => e.iter().find_map(|and| self.endless_sink(and)),
+            };
+            if sink.is_some() {
+                return sink
+            }
+        }
+        None
+    }
+
+    // the alternatives of `or` and of the expressions in it that come after an endless sink,
+    // with that sink
+    fn unreachable(&self, or: &Or, out: &mut Vec<(usize, Vec<usize>)>) {
+        for (k, and) in or.iter().enumerate() {
+            for ui in and {
+                if let Unary::Expr(e) = &self.unaries[*ui].unary {
+                    self.unreachable(e, out);
+                }
+            }
+            if k + 1 < or.len() {
+                if let Some(i) = self.endless_sink(and) {
+                    out.push((i, or[k+1..].iter().flatten().copied().collect()));
+                    break
+                }
+            }
+        }
+    }
 
-        (sinks, unaries, limited_amount)
+    // whether an expression node leads to a need with a deadline that still wants money this month
+    fn urgent(&self, src: &Source, ui: usize) -> bool {
+        let unary = &self.unaries[ui];
+        if unary.amount >= unary.capacity {
+            return false
+        }
+        match &unary.unary {
+            Unary::Val(i) => {
+                let sink = &self.sinks[*i];
+                sink.deadline.is_some() && sink.capacity > sink.amount() && !src.withheld.contains(i) && !src.too_small.contains(i)
+            }
+            Unary::Expr(e) => e.iter().flatten().any(|u| self.urgent(src, *u)),
+        }
     }
 
     fn next_step(&self) -> Step {
-        // write_err_bts("DEBUG3\n".as_bytes());
         let mut res = Step{
             src_to_snks: vec!(),
             src_to_unaries: vec!(),
+            matches: vec!(),
             amount: Fraction::infinity(),
-            stop_reason: StopReason::NodeFull,
+            // every step is limited by at least one source, so this always gets replaced
+            stop_reason: StopReason::SourceDepleted(0),
         };
 
         let mut fracs: Vec<Fraction> = self.sinks.iter().map(|_| {
-            Fraction::from(0 as i32)
+            Fraction::from(0)
+        }).collect();
+        let mut unary_fracs: Vec<Fraction> = self.unaries.iter().map(|_| {
+            Fraction::from(0)
         }).collect();
 
         for (src_id, src) in self.sources.iter().enumerate() {
-            if src.amount == Fraction::from(0 as i32) {
+            if src.amount == Fraction::from(0) {
                 continue
             }
 
-            if src.amount < res.amount {
-                res.amount = src.amount;
-                res.stop_reason = StopReason::SourceDepleted;
-            }
-
-            let (sinks, unaries, limited_amount_sink) = self.next_sinks(&src.to, src.amount);
-            // let limited_amount = amount_per_sink * Fraction::from(sinks.len());
-            // write_err_bts(format!("amount_per_sink {:?}", amount_per_sink).as_bytes());
-            // write_err_bts(format!("limited_amount_sink {:?}", limited_amount_sink).as_bytes());
-
-            if sinks.len() == 0 {
+            let (sinks, unaries) = self.next_sinks(src, &src.to);
+            if sinks.is_empty() {
                 continue
             }
 
-            let limited_amount = limited_amount_sink * Fraction::from(sinks.len());
-            if limited_amount < res.amount {
-                res.amount = limited_amount;
-                res.stop_reason = StopReason::NodeFull;
+            if src.amount < res.amount {
+                res.amount = src.amount.clone();
+                res.stop_reason = StopReason::SourceDepleted(src_id);
             }
 
-            for i in &sinks {
-                fracs[*i] += Fraction::from(1 as i32) / Fraction::from(sinks.len());
+            for (i, f) in &sinks {
+                fracs[*i] += f.clone();
+            }
+            for (i, f) in &unaries {
+                unary_fracs[*i] += f.clone();
             }
-            // write_err_bts(format!("fracs {:?}", fracs).as_bytes());
 
             res.src_to_snks.push((src_id, sinks));
             res.src_to_unaries.push((src_id, unaries));
         }
 
-        // write_err_bts("DEBUG4\n".as_bytes());
+        // matching sources add to every bit the others send to a sink as it's sent, so what
+        // they add is always the ratio of what the others gave, up to their cap, and the
+        // sink fills up as fast as both together
+        let given = fracs.clone();
+        for (src_id, src) in self.sources.iter().enumerate() {
+            for (j, m) in src.matches.iter().enumerate() {
+                if given[m.sink] == Fraction::from(0) || m.sent >= m.cap {
+                    continue
+                }
+                let rate = given[m.sink].clone() * m.ratio.clone();
+                fracs[m.sink] += rate.clone();
+                res.matches.push((src_id, j, rate.clone()));
+
+                let c = (m.cap.clone() - m.sent.clone()) / rate;
+                if c < res.amount {
+                    res.amount = c;
+                    res.stop_reason = StopReason::MatchFull(src_id, j);
+                }
+            }
+        }
+
+        for (i, frac) in unary_fracs.iter().enumerate() {
+            let u = &self.unaries[i];
+            if *frac == Fraction::from(0) || u.capacity == Fraction::infinity() {
+                continue
+            }
+            let c = (u.capacity.clone() - u.amount.clone()) / frac.clone();
+            if c < res.amount {
+                res.amount = c;
+                res.stop_reason = StopReason::NodeFull(i);
+            }
+        }
 
         for (i, frac) in fracs.iter().enumerate() {
-            // write_err_bts(format!("DEBUG5 {} {} {} {}\n", i, frac, self.sinks[i].capacity, self.sinks[i].amount).as_bytes());
-            let c = (self.sinks[i].capacity - self.sinks[i].amount()) / *frac;
-            // write_err_bts(format!("DEBUG5.1\n").as_bytes());
-            if c > Fraction::from(0 as i32) && c < res.amount {
-                // write_err_bts(format!("DEBUG5.2\n").as_bytes());
+            if *frac == Fraction::from(0) {
+                continue
+            }
+            // sinks receive what's left after the percentage fee
+            let c = (self.sinks[i].capacity.clone() - self.sinks[i].amount()) / (frac.clone() * (Fraction::from(1) - self.fee.rate.clone()));
+            if c < res.amount {
                 res.amount = c;
-                res.stop_reason = StopReason::SinkFull;
+                res.stop_reason = StopReason::SinkFull(i);
             }
-            // write_err_bts(format!("DEBUG5.3\n").as_bytes());
         }
 
-        // write_err_bts("DEBUG6\n".as_bytes());
-
         res
     }
 
+    // solves the month, then withholds conditional money from sinks that end up short and
+    // solves it again, until every sink conditional money goes to is fully funded. After
+    // that, the smallest transfer below the minimum that no trade got rid of is dropped and
+    // the month solved again, so the source sends that money to its other sinks instead.
+    // Only the steps of the last solve are passed on. Dropped sinks only grow, so this ends.
+    fn fill(&mut self, date: Option<Date>, on_step: &mut dyn FnMut(&Step)) -> Book {
+        let start = self.clone();
+        loop {
+            let mut steps = vec!();
+            let book = self.fill_once(date, &mut |s| steps.push(s.clone()));
+
+            let mut failed = false;
+            for tr in &book.transfers {
+                if !self.sources[tr.from].if_funded {
+                    continue
+                }
+                for (snk_id, _) in &tr.to {
+                    let sink = &self.sinks[*snk_id];
+                    if sink.amount() < sink.capacity {
+                        self.sources[tr.from].withheld.push(*snk_id);
+                        failed = true;
+                    }
+                }
+            }
+
+            if let (false, Some(minimum)) = (failed, &self.minimum) {
+                let smallest = book.transfers.iter()
+                    .flat_map(|tr| tr.to.iter().map(move |(snk_id, amount)| (tr.from, *snk_id, amount)))
+                    .filter(|(_, _, amount)| *amount < minimum)
+                    // of equal ones, the sink mentioned last goes first
+                    .min_by(|a, b| a.2.partial_cmp(b.2).unwrap().then(b.1.cmp(&a.1)));
+                if let Some((src_id, snk_id, _)) = smallest {
+                    self.sources[src_id].too_small.push(snk_id);
+                    failed = true;
+                }
+            }
+
+            if !failed {
+                for s in &steps {
+                    on_step(s);
+                }
+                return book
+            }
+
+            let dropped: Vec<_> = self.sources.iter().map(|s| (s.withheld.clone(), s.too_small.clone())).collect();
+            *self = start.clone();
+            for (s, (withheld, too_small)) in self.sources.iter_mut().zip(dropped) {
+                s.withheld = withheld;
+                s.too_small = too_small;
+            }
+        }
+    }
+
+    // takes steps until no source can send anything more this month, returns what went where.
+    // Needs with a deadline first get what they need this month to be funded in time, ahead
+    // of the sinks they share an AND with but after the alternatives a flow lists before
+    // them, then the month is solved as usual.
+    fn fill_once(&mut self, date: Option<Date>, on_step: &mut dyn FnMut(&Step)) -> Book {
+        let mut book = Book{transfers: vec!(), matched: vec!()};
+
+        for s in &mut self.sources {
+            s.amount = s.committed(date) + s.one_time(date);
+        }
+
+        let required: Vec<Option<Fraction>> = match date {
+            Some(d) => self.sinks.iter().map(|s| s.required(d)).collect(),
+            None => vec!(),
+        };
+        if required.iter().any(|r| r.is_some()) {
+            let capacities: Vec<Fraction> = self.sinks.iter().map(|s| s.capacity.clone()).collect();
+            for (s, r) in self.sinks.iter_mut().zip(&required) {
+                if let Some(r) = r {
+                    s.capacity = s.amount() + r.clone();
+                }
+            }
+
+            self.deadlines_first = true;
+            self.take_steps(&mut book, on_step);
+            self.deadlines_first = false;
+
+            for (s, c) in self.sinks.iter_mut().zip(capacities) {
+                s.capacity = c;
+            }
+        }
+
+        self.take_steps(&mut book, on_step);
+        self.settle(&mut book, on_step);
+        book
+    }
+
+    // trades away transfers below the minimum, or all it can when asked to keep transfers to
+    // a minimum, and takes flat fees from what sinks received.
+    // Sources with money left then make up for the fees, which can add transfers with fees
+    // of their own, so this goes on until the fees stop changing.
+    fn settle(&mut self, book: &mut Book, on_step: &mut dyn FnMut(&Step)) {
+        let mut charged = vec!(Fraction::from(0); self.sinks.len());
+        for round in 0.. {
+            if self.fewest_transfers {
+                self.consolidate(book, Fraction::infinity());
+            } else if let Some(minimum) = self.minimum.clone() {
+                self.consolidate(book, minimum);
+            }
+
+            let mut fees = vec!(Fraction::from(0); self.sinks.len());
+            let matched = book.matched.iter().map(|(src_id, j, amount)| (self.sources[*src_id].matches[*j].sink, amount.clone()));
+            for (snk_id, amount) in book.transfers.iter().flat_map(|t| t.to.iter().cloned()).chain(matched) {
+                // the percentage was already taken while solving
+                fees[snk_id] += self.fee.of(amount.clone()) - amount * self.fee.rate.clone();
+            }
+
+            let mut changed = false;
+            for (i, fee) in fees.into_iter().enumerate() {
+                if fee != charged[i] {
+                    self.sinks[i].add_amount -= fee.clone() - charged[i].clone();
+                    charged[i] = fee;
+                    changed = true;
+                }
+            }
+            if !changed || round == MAX_SETTLE_ROUNDS {
+                break
+            }
+
+            self.take_steps(book, on_step);
+        }
+    }
+
+    fn take_steps(&mut self, book: &mut Book, on_step: &mut dyn FnMut(&Step)) {
+        loop {
+            let s = self.next_step();
+            if s.src_to_snks.is_empty() {
+                break;
+            }
+
+            on_step(&s);
+
+            self.apply_step(&s);
+            book.apply_step(&s);
+        }
+    }
+
+    fn next_month(&mut self, date: Option<Date>) {
+        for s in &mut self.sources {
+            s.carry = s.carried(date);
+            // without a balance date there are no months to tell gifts apart, the undated
+            // ones are given in the first month only
+            s.gifts.retain(|g| g.date.is_some());
+        }
+        for s in &mut self.sinks {
+            s.roll_over();
+        }
+        for u in &mut self.unaries {
+            u.amount = Fraction::from(0);
+        }
+        for m in self.sources.iter_mut().flat_map(|s| &mut s.matches) {
+            m.sent = Fraction::from(0);
+        }
+        for s in &mut self.sources {
+            s.withheld.clear();
+            s.too_small.clear();
+        }
+    }
+
     fn apply_step(&mut self, s: &Step) {
-        for (src_id, sink_ids) in &s.src_to_snks {
-            self.sources[*src_id].amount -= s.amount;
+        for (src_id, sinks) in &s.src_to_snks {
+            self.sources[*src_id].amount -= s.amount.clone();
 
-            let per_sink = s.amount / Fraction::from(sink_ids.len());
-            for i in sink_ids {
-                self.sinks[*i].add_amount += per_sink;
+            for (i, share) in sinks {
+                self.sinks[*i].add_amount += s.amount.clone() * share.clone() * (Fraction::from(1) - self.fee.rate.clone());
             }
 
             for (src_id2, uns) in &s.src_to_unaries {
                 if src_id == src_id2 {
-                    for (u_id, mul) in uns {
-                        // write_err_bts(format!("DEBUG3 adding unary amount {} {} {}\n", *u_id, per_sink, *mul).as_bytes());
-                        self.unaries[*u_id].amount += per_sink * Fraction::from(*mul);
+                    for (u_id, share) in uns {
+                        self.unaries[*u_id].amount += s.amount.clone() * share.clone();
                     }
                     break;
                 }
             }
-            // write_err_bts("DEBUG2\n".as_bytes());
+        }
+
+        for (src_id, j, rate) in &s.matches {
+            let m = &mut self.sources[*src_id].matches[*j];
+            m.sent += s.amount.clone() * rate.clone();
+            self.sinks[m.sink].add_amount += s.amount.clone() * rate.clone() * (Fraction::from(1) - self.fee.rate.clone());
         }
     }
 }
 
-#[derive(Debug)]
+// what limited a step, with the index of the unary cap, source or sink, or of the
+// source and which of its matches reached its cap
+#[derive(Debug, Clone)]
 enum StopReason {
-    NodeFull,
-    SourceDepleted,
-    SinkFull,
+    NodeFull(usize),
+    SourceDepleted(usize),
+    SinkFull(usize),
+    MatchFull(usize, usize),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Step {
-    src_to_snks: Vec<(usize, Vec<usize>)>,
-    src_to_unaries: Vec<(usize, Vec<(usize, usize)>)>,
+    src_to_snks: Vec<(usize, Shares)>,
+    src_to_unaries: Vec<(usize, Shares)>,
+    // matching source, index of its match and what it adds per unit of the step amount
+    matches: Vec<(usize, usize, Fraction)>,
     amount: Fraction,
     stop_reason: StopReason,
 }
@@ -284,7 +776,9 @@ struct Transfer {
 
 #[derive(Debug)]
 struct Book {
-    transfers: Vec<Transfer>
+    transfers: Vec<Transfer>,
+    // what matching sources added, kept apart from what flowed, by source and index of the match
+    matched: Vec<(usize, usize, Fraction)>,
 }
 
 impl Book {
@@ -296,7 +790,7 @@ impl Book {
         }
 
         self.transfers.push(Transfer{
-            from: from,
+            from,
             to: vec!(),
         });
 
@@ -314,358 +808,1408 @@ impl Book {
             }
         };
 
-        to_vec.push((to, Fraction::from(0 as i32)));
+        to_vec.push((to, Fraction::from(0)));
+
+        &mut to_vec[n].1
+    }
+
+    fn amount(&self, from: usize, to: usize) -> Fraction {
+        self.transfers.iter()
+            .filter(|t| t.from == from)
+            .flat_map(|t| &t.to)
+            .find(|(k, _)| *k == to)
+            .map_or(Fraction::from(0), |(_, f)| f.clone())
+    }
 
-        return &mut to_vec[n].1
+    // drops transfers that were traded down to nothing
+    fn remove_empty(&mut self) {
+        for t in &mut self.transfers {
+            t.to.retain(|(_, f)| *f != Fraction::from(0));
+        }
+        self.transfers.retain(|t| !t.to.is_empty());
     }
 
     fn apply_step(&mut self, s: &Step) {
-        for (src_id, sink_ids) in &s.src_to_snks {
-            let per_sink = s.amount / Fraction::from(sink_ids.len());
-            for sink_id in sink_ids {
+        for (src_id, sinks) in &s.src_to_snks {
+            for (sink_id, share) in sinks {
                 let f = self.get_fraction_or_insert(*src_id, *sink_id);
-                *f += per_sink;
+                *f += s.amount.clone() * share.clone();
+            }
+        }
+
+        for (src_id, j, rate) in &s.matches {
+            let amount = s.amount.clone() * rate.clone();
+            match self.matched.iter_mut().find(|(i, k, _)| i == src_id && k == j) {
+                Some((_, _, f)) => *f += amount,
+                None => self.matched.push((*src_id, *j, amount)),
             }
         }
     }
 }
 
+/// A parsed fuko file, ready to be solved.
 #[derive(Debug)]
-struct FukoValue {
+pub struct Plan {
     source_names: Vec<String>,
     sink_names: Vec<String>,
     graph: Graph,
-    book: Book,
+    months: Option<u32>,
+    // what one unit of each currency is worth in the base currency
+    rates: Vec<(String, Fraction)>,
+    warnings: Diagnostics,
 }
 
 type Or = Vec<And>;
 type And = Vec<usize>;
+// node index and the fraction of a source's step amount it receives
+type Shares = Vec<(usize, Fraction)>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Unary {
     Val(usize),
     Expr(Or),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct UnaryCap {
     unary: Unary,
     amount: Fraction,
     capacity: Fraction,
+    weight: Fraction,
+    // part of an AND with weights or percentages, trading between sources keeps its split
+    weighted: bool,
+    // the expression as written, to name the cap when explaining
+    label: String,
 }
 
-enum ParseError {
-    Rule(Error<Rule>),
-    String(String),
+/// A single problem found in a fuko file.
+#[derive(Debug)]
+pub enum ParseError {
+    Rule(Box<Error<Rule>>),
+    Semantic(Box<Error<Rule>>),
+    InvalidUtf8(std::str::Utf8Error),
+    InvalidDate(Box<Error<Rule>>),
+    InvalidNumber(Box<Error<Rule>>),
 }
 
 impl ParseError {
-    fn to_string(&self) -> String { 
-        match self {
-            ParseError::Rule(e) => e.to_string(),
-            ParseError::String(s) => s.to_owned(),
-        }
+    fn at(span: Span, message: String) -> ParseError {
+        ParseError::Semantic(located(span, message))
     }
 }
 
-use pest::iterators::Pair;
+// points at the offending part of the file the same way pest does for syntax errors
+fn located(span: Span, message: String) -> Box<Error<Rule>> {
+    Box::new(Error::new_from_span(ErrorVariant::CustomError{ message }, span))
+}
 
-impl FukoValue {
-    fn set_sink(&mut self, ident: &str, sink: Sink) -> bool {
-        let (_, ok) = get_or_insert(&mut self.sink_names, ident);
-        if !ok {
-            return false;
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Rule(e) => write!(f, "{}", e),
+            ParseError::Semantic(e) => write!(f, "{}", e),
+            ParseError::InvalidUtf8(e) => write!(f, "the file is not valid UTF-8 after byte {}", e.valid_up_to()),
+            ParseError::InvalidDate(e) => write!(f, "{}", e),
+            ParseError::InvalidNumber(e) => write!(f, "{}", e),
         }
-
-        self.graph.sinks.push(sink);
-        true
     }
+}
 
-    fn get_or_insert_sink(&mut self, ident: &str) -> usize {
-        let (i, ok) = get_or_insert(&mut self.sink_names, ident);
-
-        if ok {
-            self.graph.sinks.push(Sink{
-                capacity: Fraction::infinity(),
-                balance: Fraction::from(0 as i32),
-                add_amount: Fraction::from(0 as i32),
-                date: None,
-                ask_for_balance: false,
-            })
-        }
+impl std::error::Error for ParseError {}
 
-        i
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
 
-    fn get_or_insert_source(&mut self, ident: &str) -> usize {
-        let (i, ok) = get_or_insert(&mut self.source_names, ident);
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub error: ParseError,
+}
 
-        if ok {
-            self.graph.sources.push(Source{
-                amount: Fraction::from(0),
-                to: vec!(),
-            })
-        }
+/// Everything wrong with a file, collected in one pass so it can be fixed in one go.
+#[derive(Debug)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>,
+}
 
-        return i;
+impl Diagnostics {
+    fn error(&mut self, error: ParseError) {
+        self.items.push(Diagnostic{ severity: Severity::Error, error });
     }
 
-    fn parse_expr(&mut self, expr: Pair<Rule>) -> Or {
-        let or_pair = expr.into_inner().next().unwrap();
-        let mut or = vec!();
+    fn warning(&mut self, error: ParseError) {
+        self.items.push(Diagnostic{ severity: Severity::Warning, error });
+    }
 
-        for and_pair in or_pair.into_inner() {
-            let mut and = vec!();
+    fn count(&self, severity: Severity) -> usize {
+        self.items.iter().filter(|d| d.severity == severity).count()
+    }
 
-            for unary_pair in and_pair.into_inner() {
-                let mut unary_inner = unary_pair.into_inner();
-                let ident_or_expr = unary_inner.next().unwrap();
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
 
-                let cap = match unary_inner.next() {
-                    Some(v) =>  Fraction::from(
-                        v.into_inner().next().unwrap().as_str()
-                         .parse::<f64>().unwrap()
-                    ),
-                    None => Fraction::infinity()
-                };
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
 
-                let uc = match ident_or_expr.as_rule() {
-                    Rule::identifier => {
-                        let s = ident_or_expr.as_str();
-                        // and.push(UnaryCap{
-                        //     unary: Unary::Val(self.get_or_insert_sink(s)),
-                        //     capacity: cap,
-                        // });
-                        UnaryCap{
-                            unary: Unary::Val(self.get_or_insert_sink(s)),
-                            amount: Fraction::from(0 as i32),
-                            capacity: cap,
-                        }
-                    }
-                    Rule:: expr => {
-                        // and.push(UnaryCap{
-                        //     unary: Unary::Expr(self.parse_expr(ident_or_expr)),
-                        //     capacity: cap,
-                        // });
-                        UnaryCap{
-                            unary: Unary::Expr(self.parse_expr(ident_or_expr)),
-                            amount: Fraction::from(0 as i32),
-                            capacity: cap,
-                        }
-                    }
-                    _ => unreachable!()
-                };
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.items.iter()
+    }
+}
 
-                self.graph.unaries.push(uc);
-                and.push(self.graph.unaries.len()-1);
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for d in &self.items {
+            match d.severity {
+                Severity::Error => writeln!(f, "error:")?,
+                Severity::Warning => writeln!(f, "warning:")?,
             }
-
-            or.push(and);
+            writeln!(f, "{}\n", d.error)?;
         }
 
-        return or;
+        let (errors, warnings) = (self.count(Severity::Error), self.count(Severity::Warning));
+        writeln!(
+            f,
+            "{} error{}, {} warning{}",
+            errors, if errors == 1 { "" } else { "s" },
+            warnings, if warnings == 1 { "" } else { "s" },
+        )
     }
 }
 
-fn get_or_insert(v: &mut Vec<String>, s: &str) -> (usize, bool) {
-    let mut i = 0;
-    loop {
-        // write_err_bts("LOOP2".as_bytes());
-        if i == v.len() {
-            break;
-        } else if v[i] == s {
-            return (i, false)
+impl std::error::Error for Diagnostics {}
+
+use pest::iterators::Pair;
+
+impl Plan {
+    /// Works out what every sink receives in the month the plan is evaluated for,
+    /// which is the month of the latest balance.
+    pub fn solve(&self) -> Allocation {
+        self.run(1, &mut |_, _| ()).pop().unwrap()
+    }
+
+    /// Solves consecutive months starting from the latest balance, carrying what
+    /// sinks have left over into the next month.
+    pub fn simulate(&self, months: u32) -> Simulation {
+        Simulation{ months: self.run(months, &mut |_, _| ()) }
+    }
+
+    /// The number of months asked for with `simulate N months`.
+    pub fn months(&self) -> Option<u32> {
+        self.months
+    }
+
+    pub fn warnings(&self) -> &Diagnostics {
+        &self.warnings
+    }
+
+    // on_step gets the index of the month and every step before it is applied
+    fn run(&self, months: u32, on_step: &mut dyn FnMut(u32, &Step)) -> Vec<Allocation> {
+        let mut graph = self.graph.clone();
+        let mut date = self.start_date();
+        let mut res = vec!();
+
+        for month in 0..months {
+            let book = graph.fill(date, &mut |s| on_step(month, s));
+            res.push(self.allocation(&graph, &book, date));
+
+            graph.next_month(date);
+            date = date.map(|d| d.next());
         }
-        i += 1;
-    };
 
-    v.push(s.to_owned());
-    (v.len()-1, true)
-}
+        res
+    }
 
-fn parse_file<'a>(bts: &'a [u8]) -> Result<FukoValue, ParseError> {
-    let file = match FukoParser::parse(Rule::file, std::str::from_utf8(&bts).unwrap()) {
-        Ok(mut f) => f.next().unwrap(),
-        Err(r) => return Err(ParseError::Rule(r)),
-    };
+    fn allocation(&self, graph: &Graph, book: &Book, date: Option<Date>) -> Allocation {
+        let mut cells = vec!();
+        for tr in &book.transfers {
+            for (snk_id, amount) in &tr.to {
+                cells.push((tr.from, *snk_id, amount.clone()));
+            }
+        }
+        // what matching sources added comes after what flowed
+        let flows = cells.len();
+        let mut matched = book.matched.clone();
+        matched.sort_by_key(|(src_id, j, _)| (*src_id, *j));
+        for (src_id, j, amount) in matched {
+            cells.push((src_id, graph.sources[src_id].matches[j].sink, amount));
+        }
 
-    let mut res = FukoValue{
-        source_names: vec!(),
-        sink_names: vec!(),
-        graph: Graph { sources: vec!(), sinks: vec!(), unaries: vec!() },
-        book: Book{transfers: vec!()},
-    };
+        let (cents, _) = rounding::round_table(graph.sources.len(), graph.sinks.len(), &cells);
+        // what arrives is rounded the same way, so sinks add up to what they receive
+        let net: Vec<_> = cells.iter().map(|(src_id, snk_id, amount)| (*src_id, *snk_id, amount.clone() - graph.fee.of(amount.clone()))).collect();
+        let (net_cents, leftover) = rounding::round_table(graph.sources.len(), graph.sinks.len(), &net);
+        // and what sources pay in their own currency, so that adds up to what they send
+        let paid: Vec<_> = cells.iter().map(|(src_id, snk_id, amount)| (*src_id, *snk_id, amount.clone() / graph.sources[*src_id].currency().1)).collect();
+        let (paid_cents, _) = rounding::round_table(graph.sources.len(), graph.sinks.len(), &paid);
+
+        let mut sent_cents = vec!(0; graph.sources.len());
+        let mut received_cents = vec!(0; graph.sinks.len());
+        let mut transfers = vec!();
+        for (i, (src_id, snk_id, amount)) in cells.into_iter().enumerate() {
+            let fee = graph.fee.of(amount.clone());
+            // the fee is what rounding leaves between the two, never below zero
+            let fee_cents = (cents[i] - net_cents[i]).max(0);
+            if i < flows {
+                sent_cents[src_id] += cents[i];
+            }
+            received_cents[snk_id] += cents[i] - fee_cents;
+            let (currency, rate) = graph.sources[src_id].currency();
+            transfers.push(TransferAllocation{
+                source: self.source_names[src_id].clone(),
+                sink: self.sink_names[snk_id].clone(),
+                amount: amount.clone(),
+                cents: cents[i],
+                fee,
+                fee_cents,
+                matching: i >= flows,
+                currency: currency.to_owned(),
+                paid: amount / rate,
+                paid_cents: if currency == BASE_CURRENCY { cents[i] } else { paid_cents[i] },
+                period: graph.sources[src_id].period,
+            });
+        }
+
+        Allocation{
+            date,
+            sources: graph.sources.iter().enumerate().map(|(i, s)| SourceAllocation{
+                name: self.source_names[i].clone(),
+                committed: s.committed(date),
+                one_time: s.one_time(date),
+                currency: s.currency().0.to_owned(),
+                period: s.period,
+                from: s.from,
+                until: s.until,
+                unspent: s.amount.clone(),
+                carried: s.carried(date),
+                // what's left once the rounded transfers are paid
+                unspent_cents: allocation::to_cents(s.committed(date) + s.one_time(date)) - sent_cents[i],
+                withheld: s.withheld.iter().map(|k| self.sink_names[*k].clone()).collect(),
+                too_small: s.too_small.iter().map(|k| self.sink_names[*k].clone()).collect(),
+            }).collect(),
+            sinks: graph.sinks.iter().enumerate().map(|(i, s)| SinkAllocation{
+                name: self.sink_names[i].clone(),
+                need: s.capacity.clone(),
+                period: s.period,
+                balance: s.balance.clone(),
+                received: s.add_amount.clone(),
+                received_cents: received_cents[i],
+                leftover_cents: leftover[i],
+                date: s.date,
+                deadline: s.deadline,
+            }).collect(),
+            transfers,
+            minimum: graph.minimum.clone(),
+        }
+    }
+
+    // simulates up to the last deadline and warns about needs that would still be short by theirs
+    fn check_deadlines(&self, diags: &mut Diagnostics, input: &str) {
+        let start = match self.start_date() {
+            Some(d) => d,
+            None => return,
+        };
+        let last = self.graph.sinks.iter().filter_map(|s| s.deadline).map(|d| start.months_until(&d)).max();
+        let months = match last {
+            Some(n) if n < MAX_MONTHS as i64 => n.max(0) as u32 + 1,
+            _ => return,
+        };
+
+        let allocations = self.run(months, &mut |_, _| ());
+        for (i, s) in self.graph.sinks.iter().enumerate() {
+            let deadline = match s.deadline {
+                Some(d) => d,
+                None => continue,
+            };
+            let sink = &allocations[start.months_until(&deadline).max(0) as usize].sinks[i];
+            if sink.remaining() > Fraction::from(0) {
+                let (start, end) = s.declared_at.unwrap();
+                diags.warning(ParseError::at(Span::new(input, start, end).unwrap(), format!(
+                    "{} can't be funded by {} at current commitments, it would still be {} short",
+                    self.sink_names[i], deadline, allocation::format_cents(sink.shortfall_cents()),
+                )));
+            }
+        }
+    }
+
+    // the latest balance date is the month the plan is evaluated for
+    fn start_date(&self) -> Option<Date> {
+        let mut res: Option<Date> = None;
+        for s in &self.graph.sinks {
+            if let Some(d) = s.date {
+                if res.is_none_or(|r| d.after(&r)) {
+                    res = Some(d);
+                }
+            }
+        }
+        res
+    }
+
+    fn set_sink(&mut self, ident: &str, sink: Sink) -> bool {
+        let (i, ok) = get_or_insert(&mut self.sink_names, ident);
+        if ok {
+            self.graph.sinks.push(sink);
+            return true
+        }
+
+        // a flow or a balance can mention the sink before its needs, keep what they set
+        let existing = &mut self.graph.sinks[i];
+        if existing.declared_at.is_some() {
+            return false
+        }
+        existing.capacity = sink.capacity;
+        existing.period = sink.period;
+        existing.deadline = sink.deadline;
+        existing.ask_for_balance = sink.ask_for_balance;
+        existing.declared_at = sink.declared_at;
+        true
+    }
+
+    fn get_or_insert_sink(&mut self, ident: &str) -> usize {
+        let (i, ok) = get_or_insert(&mut self.sink_names, ident);
+
+        if ok {
+            self.graph.sinks.push(Sink{
+                capacity: Fraction::infinity(),
+                period: None,
+                balance: Fraction::from(0),
+                add_amount: Fraction::from(0),
+                date: None,
+                deadline: None,
+                ask_for_balance: false,
+                declared_at: None,
+                referenced_at: None,
+                balance_at: None,
+            })
+        }
+
+        i
+    }
+
+    fn get_or_insert_source(&mut self, ident: &str) -> usize {
+        let (i, ok) = get_or_insert(&mut self.source_names, ident);
+
+        if ok {
+            self.graph.sources.push(Source{
+                rate: Fraction::from(0),
+                period: Period::Month,
+                from: None,
+                until: None,
+                gifts: vec!(),
+                carry: Fraction::from(0),
+                currency: None,
+                amount: Fraction::from(0),
+                to: vec!(),
+                if_funded: false,
+                withheld: vec!(),
+                too_small: vec!(),
+                matches: vec!(),
+                declared_at: None,
+                flow_at: None,
+            })
+        }
+
+        i
+    }
 
-    for stmt in file.into_inner() {
+    fn parse_statement(&mut self, stmt: Pair<Rule>) -> Result<(), ParseError> {
+        let span = stmt.as_span();
         match stmt.as_rule() {
             Rule::verb_statement => {
                 let mut inner_rules = stmt.into_inner();
                 let ident = inner_rules.next().unwrap().as_str();
                 let verb = inner_rules.next().unwrap();
-                let mut currency = inner_rules.next().unwrap().into_inner();
+                let currency = inner_rules.next().unwrap();
 
                 match verb.as_rule() {
                     Rule::needs => {
-                        let ask_for_balance = inner_rules.next();
+                        let mut currency = currency.into_inner();
+                        let (capacity, period) = self.parse_non_negative_amount(currency.next().unwrap())?;
+
+                        let mut deadline = None;
+                        let mut ask_for_balance = false;
+                        for p in inner_rules {
+                            match p.as_rule() {
+                                Rule::deadline => {
+                                    if period.is_some() {
+                                        return Err(ParseError::at(
+                                            p.as_span(),
+                                            "only one-off needs can have a deadline".to_owned(),
+                                        ))
+                                    }
+                                    deadline = Some(Date::parse(p.into_inner().next().unwrap())?);
+                                }
+                                Rule::ask_for_balance => ask_for_balance = true,
+                                _ => unreachable!()
+                            }
+                        }
 
                         let s = Sink{
-                            capacity: Fraction::from(currency.next().unwrap().into_inner()
-                                            .next().unwrap().as_str()
-                                            .parse::<f64>().unwrap()),
-                            balance: Fraction::from(0 as i32),
-                            add_amount: Fraction::from(0 as i32),
+                            capacity,
+                            period,
+                            balance: Fraction::from(0),
+                            add_amount: Fraction::from(0),
                             date: None,
-                            ask_for_balance: match ask_for_balance {
-                                Some(_) => true,
-                                None => false,
-                            }
+                            deadline,
+                            ask_for_balance,
+                            declared_at: Some((span.start(), span.end())),
+                            referenced_at: None,
+                            balance_at: None,
                         };
-                        if !res.set_sink(ident, s) {
-                            return Err(ParseError::String("recipient declared twice".to_owned()))
+                        if !self.set_sink(ident, s) {
+                            return Err(ParseError::at(span, "recipient declared twice".to_owned()))
                         }
                     }
+                    Rule::commits | Rule::gives if currency.as_rule() == Rule::onetime_currency => {
+                        let code = self.currency_of(&currency)?;
+                        let (amount, _) = self.parse_non_negative_amount(currency)?;
+
+                        let mut date = None;
+                        let mut carry_over = false;
+                        for p in inner_rules {
+                            match p.as_rule() {
+                                Rule::on => date = Some(Date::parse(p.into_inner().next().unwrap())?),
+                                Rule::carry_over => carry_over = true,
+                                _ => unreachable!()
+                            }
+                        }
+
+                        let i = self.get_or_insert_source(ident);
+                        self.graph.sources[i].set_currency(ident, span.clone(), code)?;
+                        self.graph.sources[i].gifts.push(Gift{
+                            amount,
+                            date,
+                            carry_over,
+                            at: (span.start(), span.end()),
+                        });
+                    }
                     Rule::commits => {
-                        let i = res.get_or_insert_source(ident);
-                        let s = &mut res.graph.sources[i];
-                        if s.amount != Fraction::from(0 as i32) {
-                            return Err(ParseError::String("sender declared twice".to_owned()))
+                        let i = self.get_or_insert_source(ident);
+                        if self.graph.sources[i].declared_at.is_some() {
+                            return Err(ParseError::at(span, "sender declared twice".to_owned()))
+                        }
+                        let code = self.currency_of(&currency)?;
+                        let (rate, period) = self.parse_non_negative_amount(currency)?;
+
+                        let (mut from, mut until) = (None, None);
+                        for p in inner_rules {
+                            match p.as_rule() {
+                                Rule::starts => from = Some(Date::parse(p.into_inner().next().unwrap())?),
+                                Rule::ends => until = Some(Date::parse(p.into_inner().next().unwrap())?),
+                                _ => unreachable!()
+                            }
+                        }
+                        if let (Some(f), Some(u)) = (from, until) {
+                            if f.after(&u) {
+                                return Err(ParseError::at(span, format!("{}'s commitment ends before it starts", ident)))
+                            }
                         }
-                        s.amount = Fraction::from(currency.next().unwrap().as_str()
-                                    .parse::<f64>().unwrap());
+
+                        let s = &mut self.graph.sources[i];
+                        s.set_currency(ident, span.clone(), code)?;
+                        s.rate = rate;
+                        s.period = period.unwrap();
+                        s.from = from;
+                        s.until = until;
+                        s.declared_at = Some((span.start(), span.end()));
                     }
                     _ => unreachable!()
                 }
             }
             Rule::flow_statement => {
-               let mut inner_rules = stmt.into_inner();
-
-               let ident = inner_rules.next().unwrap().as_str();
-               let expr = inner_rules.next().unwrap();
+                let mut inner_rules = stmt.into_inner();
 
-               let i = res.get_or_insert_source(ident);
-               res.graph.sources[i].to = res.parse_expr(expr);
+                let ident = inner_rules.next().unwrap().as_str();
+                let expr = inner_rules.next().unwrap();
+
+                let i = self.get_or_insert_source(ident);
+                if self.graph.sources[i].flow_at.is_some() {
+                    return Err(ParseError::at(span, format!(
+                        "flow of {} defined twice, combine both into one flow with && or ||",
+                        ident,
+                    )))
+                }
+                self.graph.sources[i].flow_at = Some((span.start(), span.end()));
+                self.graph.sources[i].to = self.parse_expr(expr)?;
+                self.graph.sources[i].if_funded = inner_rules.next().is_some();
             }
             Rule::date_verb_statement => {
                 let mut inner_rules = stmt.into_inner();
-                let date = Date::from(inner_rules.next().unwrap().as_str());
+                let date = Date::parse(inner_rules.next().unwrap())?;
                 let ident = inner_rules.next().unwrap().as_str();
-                let onetime_currency = inner_rules.next().unwrap().as_str().parse::<f64>().unwrap();
+                let (balance, _) = self.parse_amount(inner_rules.next().unwrap())?;
 
-                let i = res.get_or_insert_sink(ident);
-                if match &res.graph.sinks[i].date {
-                    Some(d) => date.after(&d),
+                let i = self.get_or_insert_sink(ident);
+                let sink = &mut self.graph.sinks[i];
+                if match &sink.date {
+                    Some(d) => date.after(d),
                     None => true,
                 } {
-                    res.graph.sinks[i].date = Some(date);
-                    res.graph.sinks[i].balance = Fraction::from(onetime_currency);
+                    sink.date = Some(date);
+                    sink.balance = balance;
+                    sink.balance_at = Some((span.start(), span.end()));
+                }
+            }
+            Rule::simulate_statement => {
+                if self.months.is_some() {
+                    return Err(ParseError::at(span, "simulation declared twice".to_owned()))
+                }
+                let months = stmt.into_inner().next().unwrap();
+                self.months = match months.as_str().parse::<u32>() {
+                    Ok(n) if n > 0 && n <= MAX_MONTHS => Some(n),
+                    _ => return Err(ParseError::InvalidNumber(located(
+                        months.as_span(),
+                        format!("can only simulate 1 to {} months", MAX_MONTHS),
+                    ))),
+                };
+            }
+            Rule::rate_statement => {
+                let mut inner_rules = stmt.into_inner();
+                let code = inner_rules.next().unwrap();
+                let number = inner_rules.next().unwrap();
+                let base = inner_rules.next().unwrap();
+
+                if code.as_str() == BASE_CURRENCY {
+                    return Err(ParseError::at(code.as_span(), format!("{} is the base currency", BASE_CURRENCY)))
+                }
+                if base.as_str() != BASE_CURRENCY {
+                    return Err(ParseError::at(base.as_span(), format!(
+                        "rates are given in the base currency\n\ne.g.:\nrate {} = 1 {}",
+                        code.as_str(), BASE_CURRENCY,
+                    )))
+                }
+                if self.rates.iter().any(|(c, _)| c == code.as_str()) {
+                    return Err(ParseError::at(span, format!("rate for {} declared twice", code.as_str())))
+                }
+
+                let number_span = number.as_span();
+                let rate = parse_number(number, MAX_RATE_DECIMALS)?;
+                if rate <= Fraction::from(0) {
+                    return Err(ParseError::at(number_span, "rate must be positive".to_owned()))
                 }
+                self.rates.push((code.as_str().to_owned(), rate));
             }
-            Rule::EOI => (),
+            Rule::fee_statement => {
+                if self.graph.fee.declared {
+                    return Err(ParseError::at(span, "fee declared twice".to_owned()))
+                }
+                let mut fee = Fee{ flat: Fraction::from(0), rate: Fraction::from(0), declared: true };
+                let (mut flat, mut percentage) = (false, false);
+                for p in stmt.into_inner() {
+                    let part = p.as_span();
+                    if p.as_rule() == Rule::percentage {
+                        if percentage {
+                            return Err(ParseError::at(part, "a fee has at most one percentage\n\ne.g.:\nfee 1.5% + €0.30".to_owned()))
+                        }
+                        percentage = true;
+                        fee.rate = parse_number(p.into_inner().next().unwrap(), MAX_DECIMALS)? / Fraction::from(100);
+                        // at 100% nothing would ever arrive
+                        if fee.rate < Fraction::from(0) || fee.rate >= Fraction::from(1) {
+                            return Err(ParseError::at(part, "a percentage fee must be at least 0% and below 100%".to_owned()))
+                        }
+                    } else {
+                        if flat {
+                            return Err(ParseError::at(part, "a fee has at most one flat amount\n\ne.g.:\nfee 1.5% + €0.30".to_owned()))
+                        }
+                        flat = true;
+                        fee.flat = self.parse_non_negative_amount(p)?.0;
+                    }
+                }
+                self.graph.fee = fee;
+            }
+            Rule::minimum_statement => {
+                if self.graph.minimum.is_some() {
+                    return Err(ParseError::at(span, "minimum transfer declared twice".to_owned()))
+                }
+                self.graph.minimum = Some(self.parse_non_negative_amount(stmt.into_inner().next().unwrap())?.0);
+            }
+            Rule::match_statement => {
+                let mut inner_rules = stmt.into_inner();
+                let ident = inner_rules.next().unwrap().as_str();
+                let sink = inner_rules.next().unwrap();
+                let label = span.as_str()[sink.as_span().start() - span.start()..].split_whitespace().collect::<Vec<_>>().join(" ");
+
+                let ratio = inner_rules.next().unwrap();
+                let ratio_span = ratio.as_span();
+                let mut parts = ratio.into_inner();
+                let (theirs, others) = (parse_number(parts.next().unwrap(), MAX_DECIMALS)?, parse_number(parts.next().unwrap(), MAX_DECIMALS)?);
+                if theirs <= Fraction::from(0) || others <= Fraction::from(0) {
+                    return Err(ParseError::at(ratio_span, "both sides of a ratio must be positive".to_owned()))
+                }
+
+                let i = self.get_or_insert_source(ident);
+                let mut cap = Fraction::infinity();
+                if let Some(p) = inner_rules.next() {
+                    let currency = p.into_inner().next().unwrap();
+                    let code = self.currency_of(&currency)?;
+                    cap = self.parse_non_negative_amount(currency)?.0;
+                    self.graph.sources[i].set_currency(ident, span.clone(), code)?;
+                }
+
+                let k = self.get_or_insert_sink(sink.as_str());
+                if self.graph.sources[i].matches.iter().any(|m| m.sink == k) {
+                    return Err(ParseError::at(span, format!("{} already matches {}", ident, sink.as_str())))
+                }
+                self.graph.sources[i].matches.push(Match{
+                    sink: k,
+                    ratio: theirs / others,
+                    cap,
+                    sent: Fraction::from(0),
+                    label,
+                    at: (span.start(), span.end()),
+                });
+            }
+            Rule::minimise_statement => {
+                if self.graph.fewest_transfers {
+                    return Err(ParseError::at(span, "minimise transfers declared twice".to_owned()))
+                }
+                self.graph.fewest_transfers = true;
+            }
+            Rule::comment | Rule::EOI => (),
             _ => unreachable!(),
         }
+
+        Ok(())
     }
 
-    for (i, s) in res.graph.sinks.iter().enumerate() {
-        if s.ask_for_balance && match s.date {Some(_) => false, None => true} {
-            let name = &res.sink_names[i];
-            return Err(ParseError::String(format!(
-                "please provide a balance for {}\n\ne.g.:\nYYYY-MM {} had 10",
-                name, name
-            )));
+    // the currency code of an amount and what one unit of it is worth in the base currency
+    fn currency_of(&self, pair: &Pair<Rule>) -> Result<(String, Fraction), ParseError> {
+        let mut symbol = None;
+        let mut code = None;
+        for p in pair.clone().into_inner() {
+            match p.as_rule() {
+                Rule::symbol => symbol = Some(match p.as_str() {
+                    "$" => "USD",
+                    "£" => "GBP",
+                    _ => "EUR",
+                }),
+                Rule::code => code = Some(p),
+                _ => (),
+            }
         }
+
+        let code = match (symbol, code) {
+            (Some(s), Some(c)) if s != c.as_str() => {
+                return Err(ParseError::at(c.as_span(), format!("{} doesn't match the currency symbol", c.as_str())))
+            }
+            (_, Some(c)) => c,
+            (Some(s), None) => return self.rate_of(s, pair.as_span()),
+            (None, None) => return Ok((BASE_CURRENCY.to_owned(), Fraction::from(1))),
+        };
+        self.rate_of(code.as_str(), code.as_span())
     }
 
-    Ok(res)
-}
+    fn rate_of(&self, code: &str, span: Span) -> Result<(String, Fraction), ParseError> {
+        if code == BASE_CURRENCY {
+            return Ok((code.to_owned(), Fraction::from(1)))
+        }
+        match self.rates.iter().find(|(c, _)| c == code) {
+            Some((_, rate)) => Ok((code.to_owned(), rate.clone())),
+            None => Err(ParseError::at(span, format!(
+                "there is no rate for {}\n\ne.g.:\nrate {} = 1 {}",
+                code, code, BASE_CURRENCY,
+            ))),
+        }
+    }
 
-fn main() {
-    let bts = read_all();
-    if bts.len() == 0 {
-        return;
+    // parses a periodic_currency or onetime_currency into the base currency,
+    // periodic amounts are normalized to a month
+    fn parse_amount(&self, pair: Pair<Rule>) -> Result<(Fraction, Option<Period>), ParseError> {
+        let (_, rate) = self.currency_of(&pair)?;
+        let mut amount = Fraction::from(0);
+        let mut period = None;
+        for p in pair.into_inner() {
+            match p.as_rule() {
+                Rule::number => {
+                    let span = p.as_span();
+                    amount = parse_number(p, MAX_DECIMALS)? * rate.clone();
+                    // converted amounts are held to the same limit as amounts in the base currency
+                    if amount.abs() >= max_amount() {
+                        return Err(ParseError::InvalidNumber(located(span.clone(), format!(
+                            "{} is too large to calculate with in {}", span.as_str(), BASE_CURRENCY,
+                        ))))
+                    }
+                }
+                Rule::period => period = Some(Period::from(p.as_str())),
+                _ => (),
+            }
+        }
+
+        Ok(match period {
+            Some(period) => (period.per_month(amount), Some(period)),
+            None => (amount, None),
+        })
     }
 
-   let mut fv = match parse_file(&bts) {
-        Ok(v) => {
-            // write_err_bts(format!("{:?}", v).as_bytes());
-            v
+    // only balances can be negative, a negative need, commitment or cap would keep the solver going forever
+    fn parse_non_negative_amount(&self, pair: Pair<Rule>) -> Result<(Fraction, Option<Period>), ParseError> {
+        let span = pair.as_span();
+        let res = self.parse_amount(pair)?;
+        if res.0 < Fraction::from(0) {
+            return Err(ParseError::InvalidNumber(located(span, "amount can't be negative".to_owned())))
         }
-        Err(e) => {
-            write_bts(e.to_string().as_bytes());
-            return
+        Ok(res)
+    }
+
+    fn parse_expr(&mut self, expr: Pair<Rule>) -> Result<Or, ParseError> {
+        let or_pair = expr.into_inner().next().unwrap();
+        let mut or = vec!();
+
+        for and_pair in or_pair.into_inner() {
+            let mut and = vec!();
+            let and_span = and_pair.as_span();
+            // the percentages of the AND and whether some part has none, or a weight instead
+            let mut percentages = vec!();
+            let mut unpercented = false;
+            let mut weighted = false;
+
+            for unary_pair in and_pair.into_inner() {
+                let label = unary_pair.as_str().trim().to_owned();
+                let mut unary_inner = unary_pair.into_inner();
+                let ident_or_expr = unary_inner.next().unwrap();
+
+                let mut cap = Fraction::infinity();
+                let mut weight = Fraction::from(1);
+                let mut percentage = false;
+                for p in unary_inner {
+                    match p.as_rule() {
+                        Rule::periodic_currency => cap = self.parse_non_negative_amount(p)?.0,
+                        Rule::weight => {
+                            // a percentage is just another way to write a weight, "75%" weighs as much as "*75"
+                            let span = p.as_span();
+                            percentage = span.as_str().ends_with('%');
+                            weighted = true;
+                            weight = parse_number(p.into_inner().next().unwrap(), MAX_DECIMALS)?;
+                            if weight <= Fraction::from(0) {
+                                return Err(ParseError::at(span, "weight must be positive".to_owned()))
+                            }
+                        }
+                        _ => unreachable!()
+                    }
+                }
+
+                let unary = match ident_or_expr.as_rule() {
+                    Rule::identifier => {
+                        let span = ident_or_expr.as_span();
+                        let i = self.get_or_insert_sink(span.as_str());
+                        let sink = &mut self.graph.sinks[i];
+                        if sink.referenced_at.is_none() {
+                            sink.referenced_at = Some((span.start(), span.end()));
+                        }
+                        Unary::Val(i)
+                    }
+                    Rule::expr => Unary::Expr(self.parse_expr(ident_or_expr)?),
+                    _ => unreachable!()
+                };
+
+                self.graph.unaries.push(UnaryCap{
+                    unary,
+                    amount: Fraction::from(0),
+                    capacity: cap,
+                    weight: weight.clone(),
+                    weighted: false,
+                    label,
+                });
+                and.push(self.graph.unaries.len()-1);
+
+                if percentage {
+                    percentages.push(weight);
+                } else {
+                    unpercented = true;
+                }
+            }
+
+            for ui in &and {
+                self.graph.unaries[*ui].weighted = weighted;
+            }
+
+            // percentages that don't add up would quietly be scaled to, 75% and 50% splitting 60/40
+            if !percentages.is_empty() {
+                if unpercented {
+                    return Err(ParseError::at(and_span, "either every part of an AND gets a percentage or none does\n\ne.g.:\nA -> x 75% && y 25%".to_owned()))
+                }
+                if percentages.into_iter().sum::<Fraction>() != Fraction::from(100) {
+                    return Err(ParseError::at(and_span, "percentages in an AND must add up to 100%\n\ne.g.:\nA -> x 75% && y 25%".to_owned()))
+                }
+            }
+
+            or.push(and);
         }
+
+        Ok(or)
+    }
+}
+
+// parses the integer and decimal digits of a number exactly, going through f64
+// would turn amounts like 0.1 into binary fractions with huge denominators
+fn parse_number(pair: Pair<Rule>, max_decimals: usize) -> Result<Fraction, ParseError> {
+    let s = pair.as_str();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (int, dec) = match digits.find('.') {
+        Some(i) => (&digits[..i], &digits[i+1..]),
+        None => (digits, ""),
     };
 
+    // keep totals within what fits in cents when reporting
+    if int.len() > MAX_DIGITS {
+        return Err(ParseError::InvalidNumber(located(pair.as_span(), format!("{} is too large to calculate with", s))))
+    }
+    if dec.len() > max_decimals {
+        return Err(ParseError::InvalidNumber(located(
+            pair.as_span(),
+            format!("{} has more than {} decimal places", s, max_decimals),
+        )))
+    }
+
+    // rates have more digits than fit in 64 bits
+    let mut numer = BigUint::from(0u32);
+    let mut denom = BigUint::from(1u32);
+    for c in int.chars().chain(dec.chars()) {
+        numer = numer * 10u32 + c.to_digit(10).unwrap();
+    }
+    for _ in dec.chars() {
+        denom *= 10u32;
+    }
+
+    Ok(if negative {
+        Fraction::new_neg(numer, denom)
+    } else {
+        Fraction::new(numer, denom)
+    })
+}
+
+// the first amount with more integer digits than MAX_DIGITS
+fn max_amount() -> Fraction {
+    let mut res = Fraction::from(1);
+    for _ in 0..MAX_DIGITS {
+        res *= Fraction::from(10);
+    }
+    res
+}
+
+fn get_or_insert(v: &mut Vec<String>, s: &str) -> (usize, bool) {
+    let mut i = 0;
     loop {
-        // write_err_bts(format!("LOOP1 {:?}", fv.graph).as_bytes());
-        let s = fv.graph.next_step();
-        if s.src_to_snks.len() == 0 {
+        // write_err_bts("LOOP2".as_bytes());
+        if i == v.len() {
             break;
+        } else if v[i] == s {
+            return (i, false)
         }
+        i += 1;
+    };
 
-        // write_err_bts("\n".as_bytes());
-        write_err_bts(format!("{:?}\n", s).as_bytes());
+    v.push(s.to_owned());
+    (v.len()-1, true)
+}
 
-        fv.graph.apply_step(&s);
-        fv.book.apply_step(&s);
+/// Like [`parse`], for input that may not be valid UTF-8.
+pub fn parse_bytes(bts: &[u8]) -> Result<Plan, Diagnostics> {
+    match std::str::from_utf8(bts) {
+        Ok(s) => parse(s),
+        Err(e) => {
+            let mut diags = Diagnostics{ items: vec!() };
+            diags.error(ParseError::InvalidUtf8(e));
+            Err(diags)
+        }
     }
+}
+
+/// Parses a fuko file. Warnings are available from the plan, errors come back
+/// together with any warnings.
+pub fn parse(input: &str) -> Result<Plan, Diagnostics> {
+    let mut diags = Diagnostics{ items: vec!() };
+
+    let file = match FukoParser::parse(Rule::file, input) {
+        Ok(mut f) => f.next().unwrap(),
+        Err(r) => {
+            diags.error(ParseError::Rule(Box::new(r)));
+            return Err(diags)
+        }
+    };
+
+    let mut res = Plan{
+        source_names: vec!(),
+        sink_names: vec!(),
+        graph: Graph {
+            sources: vec!(),
+            sinks: vec!(),
+            unaries: vec!(),
+            fee: Fee{ flat: Fraction::from(0), rate: Fraction::from(0), declared: false },
+            minimum: None,
+            fewest_transfers: false,
+            deadlines_first: false,
+        },
+        months: None,
+        rates: vec!(),
+        warnings: Diagnostics{ items: vec!() },
+    };
+
+    let mut simulate_span = None;
+    // names in statements that failed, what they would have declared is missing, so
+    // warnings about that would only repeat the error
+    let mut failed = vec!();
+
+    // rates come first so amounts can be converted wherever they are declared
+    let (rates, stmts): (Vec<_>, Vec<_>) = file.into_inner().partition(|s| s.as_rule() == Rule::rate_statement);
+    for stmt in rates.into_iter().chain(stmts) {
+        if stmt.as_rule() == Rule::simulate_statement {
+            simulate_span = Some(stmt.as_span());
+        }
+        let names = stmt.clone().into_inner().flatten().filter(|p| p.as_rule() == Rule::identifier);
+        let names: Vec<&str> = names.map(|p| p.as_str()).collect();
+        if let Err(e) = res.parse_statement(stmt) {
+            diags.error(e);
+            failed.extend(names);
+        }
+    }
+
+    let at = |(start, end)| Span::new(input, start, end).unwrap();
 
-    // print what sinks receive
-    for (i, s) in fv.graph.sinks.iter().enumerate() {
-        match &s.date {
-            Some(d) => {
-                write_bts(format!(
-                    "{} <- {:.2} for {}\n",
-                    fv.sink_names[i].as_str(),
-                    s.add_amount,
-                    d.to_string(),
-                ).as_bytes());
+    if let (Some(span), Some(_)) = (simulate_span, res.months) {
+        if res.start_date().is_none() {
+            diags.error(ParseError::at(
+                span,
+                "please provide a balance to start the simulation from\n\ne.g.:\nYYYY-MM rent had 0".to_owned(),
+            ));
+        }
+    }
+
+    for (i, s) in res.graph.sinks.iter().enumerate() {
+        let name = &res.sink_names[i];
+        if s.deadline.is_some() && res.start_date().is_none() {
+            diags.error(ParseError::at(at(s.declared_at.unwrap()), format!(
+                "please provide a balance to count the months to {}'s deadline from\n\ne.g.:\nYYYY-MM {} had 0",
+                name, name
+            )));
+        }
+        if s.ask_for_balance && s.date.is_none() {
+            diags.error(ParseError::at(at(s.declared_at.unwrap()), format!(
+                "please provide a balance for {}\n\ne.g.:\nYYYY-MM {} had 10",
+                name, name
+            )));
+        }
+        if failed.contains(&name.as_str()) {
+            continue
+        }
+        if s.declared_at.is_none() {
+            if let Some(r) = s.referenced_at {
+                diags.warning(ParseError::at(at(r), format!(
+                    "{} has no needs declared and will take any amount\n\ne.g.:\n{} needs €10/month",
+                    name, name
+                )));
             }
-            None => {
-                write_bts(format!(
-                    "{} <- {:.2}\n",
-                    fv.sink_names[i].as_str(),
-                    s.add_amount,
-                ).as_bytes());
+        }
+        if s.referenced_at.is_none() {
+            if let Some(d) = s.declared_at.or(s.balance_at) {
+                diags.warning(ParseError::at(at(d), format!(
+                    "no flow goes to {}, so it never receives anything\n\ne.g.:\n{} -> {}",
+                    name, res.source_names.first().map(|n| n.as_str()).unwrap_or("A"), name
+                )));
             }
         }
     }
 
-    write_bts("\n".as_bytes());
+    for (i, s) in res.graph.sources.iter().enumerate() {
+        if (s.from.is_some() || s.until.is_some()) && res.start_date().is_none() {
+            let name = &res.source_names[i];
+            diags.error(ParseError::at(at(s.declared_at.unwrap()), format!(
+                "please provide a balance to tell whether {}'s commitment has started\n\ne.g.:\nYYYY-MM {} had 0",
+                name, res.sink_names.first().map(|n| n.as_str()).unwrap_or("rent")
+            )));
+        }
+        for g in &s.gifts {
+            let name = &res.source_names[i];
+            match (g.date, res.start_date()) {
+                (Some(_), None) => diags.error(ParseError::at(at(g.at), format!(
+                    "please provide a balance to tell when {}'s gift is available\n\ne.g.:\nYYYY-MM {} had 0",
+                    name, res.sink_names.first().map(|n| n.as_str()).unwrap_or("rent")
+                ))),
+                (Some(d), Some(start)) if start.after(&d) => diags.warning(ParseError::at(at(g.at), format!(
+                    "{}'s gift on {} is before {}, the first month the plan is evaluated for, so it's never used",
+                    name, d, start
+                ))),
+                _ => (),
+            }
+        }
+        if let (Some(m), true) = (s.matches.first(), s.declared_at.is_some() || !s.gifts.is_empty() || s.flow_at.is_some()) {
+            let name = &res.source_names[i];
+            diags.error(ParseError::at(at(m.at), format!(
+                "{} matches what others give, so it can't also commit or have a flow of its own",
+                name
+            )));
+        }
+        if failed.contains(&res.source_names[i].as_str()) {
+            continue
+        }
+        for m in s.matches.iter().filter(|m| res.graph.sinks[m.sink].referenced_at.is_none()) {
+            diags.warning(ParseError::at(at(m.at), format!(
+                "no flow goes to {}, so {} never has anything to match",
+                res.sink_names[m.sink], res.source_names[i]
+            )));
+        }
+        if let (None, true, Some(flow_at)) = (s.declared_at, s.gifts.is_empty(), s.flow_at) {
+            let name = &res.source_names[i];
+            diags.warning(ParseError::at(at(flow_at), format!(
+                "{} never commits anything\n\ne.g.:\n{} commits €10/month",
+                name, name
+            )));
+        }
+        // conditional money skips sinks that can't be funded, those never can
+        let mut unreachable = vec!();
+        if !s.if_funded {
+            res.graph.unreachable(&s.to, &mut unreachable);
+        }
+        for (k, after) in unreachable.into_iter().filter(|(k, _)| !failed.contains(&res.sink_names[*k].as_str())) {
+            let labels: Vec<&str> = after.iter().map(|ui| res.graph.unaries[*ui].label.as_str()).collect();
+            let name = &res.sink_names[k];
+            diags.warning(ParseError::at(at(s.flow_at.unwrap()), format!(
+                "{}'s flow never reaches {}, {} comes first and takes any amount\n\ne.g.:\n{} needs €10/month",
+                res.source_names[i], labels.join(", "), name, name
+            )));
+        }
+        if let (Some(declared_at), None) = (s.declared_at.or(s.gifts.first().map(|g| g.at)), s.flow_at) {
+            let name = &res.source_names[i];
+            diags.warning(ParseError::at(at(declared_at), format!(
+                "{} has no flow, so its commitment is never spent\n\ne.g.:\n{} -> {}",
+                name, name, res.sink_names.first().map(|n| n.as_str()).unwrap_or("rent")
+            )));
+        }
+    }
 
-    // print transfers
-    for tr in fv.book.transfers {
-        for (snk_id, amount) in tr.to {
-            match &fv.graph.sinks[snk_id].date {
-                Some(d) => {
-                    write_bts(format!(
-                        "{} -> {}: {:.2} for {}\n",
-                        fv.source_names[tr.from].as_str(),
-                        fv.sink_names[snk_id].as_str(),
-                        amount,
-                        d.to_string(),
-                    ).as_bytes());
-                }
-                None => {
-                    write_bts(format!(
-                        "{} -> {}: {:.2}\n",
-                        fv.source_names[tr.from].as_str(),
-                        fv.sink_names[snk_id].as_str(),
-                        amount
-                    ).as_bytes());
-                }
+    // gifts without a date are given in the first month the plan is evaluated for
+    let start = res.start_date();
+    for s in &mut res.graph.sources {
+        for g in &mut s.gifts {
+            if g.date.is_none() {
+                g.date = start;
             }
         }
-        write_bts("\n".as_bytes());
     }
-}
 
-extern "C" {
-    fn read(p: *const u8, l: usize) -> usize;
-    fn write(p: *const u8, l: usize);
-    fn write_err(p: *const u8, l: usize);
+    if diags.has_errors() {
+        return Err(diags)
+    }
+
+    res.check_deadlines(&mut diags, input);
+
+    res.warnings = diags;
+    Ok(res)
 }
 
-#[no_mangle]
-pub extern "C" fn _start() {
-    main();
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLOW: &str = "\nA commits €10/month\nA -> a\n";
+
+    fn fails(input: &str) {
+        assert!(parse(input).is_err(), "{:?} should not parse", input);
+    }
+
+    #[test]
+    fn rejects_invalid_months() {
+        fails(&format!("a needs €10 by 2022-13{}", FLOW));
+        fails(&format!("a needs €10 by 2022-00{}", FLOW));
+    }
+
+    #[test]
+    fn rejects_huge_numbers() {
+        fails(&format!("a needs €10 by 20220000000000000000-01{}", FLOW));
+        fails(&format!("simulate 99999999999999999999 months\na needs €10/month{}", FLOW));
+        fails(&format!("a needs €10000000000000/month{}", FLOW));
+        fails("rate USD = 999999999999.9999 EUR\nA commits $999999999999.9999/day\nA -> x\n");
+        let allocation = parse("rate USD = 999999999999.99999999 EUR\nA commits $1/month\nA -> x\n").unwrap().solve();
+        assert_eq!(allocation.sinks[0].received_cents, 100000000000000);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert!(parse_bytes(b"a needs \xff\xfe/month\n").is_err());
+    }
+
+    #[test]
+    fn rejects_negative_amounts() {
+        fails(&format!("a needs €-5/month{}", FLOW));
+        fails("a needs €5/month\nA commits €-5/month\nA -> a\n");
+    }
+
+    #[test]
+    fn rejects_too_many_decimals() {
+        fails(&format!("a needs €1.00001/month{}", FLOW));
+    }
+
+    // the products of these used to overflow 64 bit fractions
+    #[test]
+    fn solves_awkward_amounts() {
+        let plan = parse("a needs €1000.01/week
+b needs €777.77/quarter
+d needs €5000.55 by 2023-06
+2022-09 d had €12.34
+c needs €333.33/day
+A commits €1234.57/week
+B commits €999.99/day
+A -> (a 99.9999% && b 0.0001%) && c*7
+B -> a && c*17 && d*3
+").unwrap();
+        let allocation = plan.solve();
+        assert_eq!(allocation.sinks.len(), 4);
+        plan.simulate(24);
+    }
+
+    #[test]
+    fn rejects_percentages_that_dont_add_up() {
+        fails("A commits €10/month\nA -> x 75% && y 50%\n");
+        fails("A commits €10/month\nA -> x 10% && y*3\n");
+        fails("A commits €10/month\nA -> x 10% && y\n");
+        fails("a needs €1000.01/week
+b needs €777.77/quarter
+c needs €333.33/day
+A commits €1234.57/week
+B commits €999.99/day
+A -> a 99.9999% && b 0.0001% && c*7
+B -> a && c*17
+");
+        assert!(parse("A commits €10/month\nA -> (x 60% && y 40%) && z*2\n").is_ok());
+    }
+
+    fn number(s: &str) -> Fraction {
+        parse_number(FukoParser::parse(Rule::number, s).unwrap().next().unwrap(), MAX_DECIMALS).unwrap()
+    }
+
+    #[test]
+    fn parses_decimals_exactly() {
+        assert_eq!(number("0.1"), Fraction::new(1u64, 10u64));
+        assert_eq!(number("33.33"), Fraction::new(3333u64, 100u64));
+        assert_eq!(number("0.1") + number("0.2"), number("0.3"));
+    }
+
+    #[test]
+    fn splits_exactly() {
+        let allocation = parse("A commits €100/month\nA -> a && b && c\n").unwrap().solve();
+        let total: Fraction = allocation.transfers.iter().map(|t| t.amount.clone()).sum();
+        assert_eq!(total, Fraction::from(100));
+        assert_eq!(allocation.transfers.iter().map(|t| t.cents).sum::<i64>(), 10000);
+    }
+
+    #[test]
+    fn rounds_what_sources_send_to_what_they_commit() {
+        let allocation = parse("x needs €1000/month
+A commits €10.005/month
+B commits €10.005/month
+C commits €10.005/month
+A -> x
+B -> x
+C -> x
+").unwrap().solve();
+        assert!(allocation.sources.iter().all(|s| s.unspent_cents == 0));
+        let x = &allocation.sinks[0];
+        assert_eq!(x.received_cents + x.shortfall_cents(), 100000);
+    }
+
+    #[test]
+    fn rounds_paid_amounts_to_what_sources_pay() {
+        let allocation = parse("rate USD = 0.92765 EUR
+A commits $100/month
+B commits €100/month
+A -> a && b && c
+B -> a && b && c
+").unwrap().solve();
+        for source in ["A", "B"] {
+            let transfers = allocation.transfers.iter().filter(|t| t.source == source);
+            assert_eq!(transfers.map(|t| t.paid_cents).sum::<i64>(), 10000);
+        }
+        assert!(allocation.transfers.iter().filter(|t| t.currency == BASE_CURRENCY).all(|t| t.paid_cents == t.cents));
+    }
+
+    #[test]
+    fn sinks_csv_rows_add_up() {
+        let plan = parse("x needs €10/week\nA commits €5/week\nA -> x\n2022-01 x had 0\nsimulate 3 months\n").unwrap();
+        let csv = plan.simulate(plan.months().unwrap()).sinks_csv();
+        for row in csv.lines().skip(1) {
+            let cells: Vec<_> = row.split(',').collect();
+            assert_eq!(cells[1..5], ["43.33", "0.00", "21.67", "21.66"]);
+        }
+    }
+
+    #[test]
+    fn merges_slivers_below_the_minimum() {
+        let allocation = parse("A commits €8/month
+A -> a && b && c && d
+minimum transfer €5
+fee €0.50
+").unwrap().solve();
+        assert_eq!(allocation.transfers.len(), 1);
+        assert_eq!(allocation.transfers[0].cents, 800);
+        assert_eq!(allocation.sources[0].too_small, ["d", "c", "b"]);
+    }
+
+    #[test]
+    fn deadlines_keep_flow_order() {
+        let plan = "rent needs €800/month
+car needs €9000 by 2023-03
+2022-09 car had €0
+B commits €1500/month
+";
+        let received = |flow: &str| -> Vec<i64> {
+            let allocation = parse(&format!("{}{}\n", plan, flow)).unwrap().solve();
+            allocation.sinks.iter().map(|s| s.received_cents).collect()
+        };
+        assert_eq!(received("B -> rent || car"), [80000, 70000]);
+        // the car gets the 9000 spread over the 7 months left first
+        assert_eq!(received("B -> rent && car"), [21429, 128571]);
+    }
+
+    #[test]
+    fn trades_keep_weights() {
+        let allocation = parse("x needs €100/month
+y needs €100/month
+A commits €100/month
+B commits €100/month
+A -> x*3 && y*1
+B -> x && y
+minimise transfers
+").unwrap().solve();
+        assert_eq!(allocation.transfers.iter().filter(|t| t.source == "A").count(), 2);
+    }
+
+    #[test]
+    fn accepts_needs_after_a_flow() {
+        let plan = parse("A commits €10/month\nA -> x || y\nx needs €4/month\n2022-01 y had €1\ny needs €20/month\n").unwrap();
+        assert!(plan.warnings().is_empty());
+        let allocation = plan.solve();
+        assert_eq!(allocation.sinks.iter().map(|s| s.received_cents).collect::<Vec<_>>(), [400, 600]);
+    }
+
+    #[test]
+    fn warns_about_fallbacks_after_unlimited_sinks() {
+        let plan = parse("A commits €10/month\nA -> x || y\ny needs €4/month\n").unwrap();
+        assert!(plan.warnings().iter().any(|d| d.error.to_string().contains("A's flow never reaches y")));
+    }
+
+    #[test]
+    fn errors_dont_cause_follow_up_warnings() {
+        let diags = parse("rent needs 100 XYZ/month\nA commits 50 XYZ/month\nA -> rent\n").unwrap_err();
+        assert!(diags.iter().all(|d| d.severity == Severity::Error));
+    }
+
+    #[test]
+    fn gives_undated_gifts_once() {
+        let received = |input: &str, months: u32| -> Vec<i64> {
+            let simulation = parse(input).unwrap().simulate(months);
+            simulation.months.iter().map(|a| a.sinks[0].received_cents).collect()
+        };
+        assert_eq!(received("x needs €1000\nA gives €300\nA -> x\n", 3), [30000, 0, 0]);
+        assert_eq!(received("x needs €1000\nA gives €300, carry over\nA -> x(€100/month)\n", 4), [10000, 10000, 10000, 0]);
+    }
+
+    const UNFORMATTED: &str = "// household plan
+// shared by both of us
+
+A -> rent && food*2 // most goes to food
+// rent is due on the first
+rent needs 800/month
+2022-9 food had €20
+2022-03 rent had 0
+food needs €300.5/month // groceries
+A commits €1000/month
+B commits 200 / week
+B -> food || rent
+";
+
+    #[test]
+    fn formatting_keeps_comments() {
+        let formatted = format(UNFORMATTED).unwrap();
+        assert!(formatted.starts_with("// household plan\n// shared by both of us\n\n"));
+        assert!(formatted.contains("// rent is due on the first\nrent needs €800/month\n"));
+        assert!(formatted.contains("food needs €300.50/month // groceries\n"));
+        assert!(formatted.contains("A -> rent && food*2 // most goes to food\n"));
+        assert!(formatted.contains("2022-03 rent had €0\n2022-09 food had €20\n"));
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let formatted = format(UNFORMATTED).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn formatting_keeps_the_plan() {
+        let received = |input: &str| -> Vec<(String, i64)> {
+            let allocation = parse(input).unwrap().solve();
+            allocation.sinks.into_iter().map(|s| (s.name, s.received_cents)).collect()
+        };
+        let mut before = received(UNFORMATTED);
+        let mut after = received(&format(UNFORMATTED).unwrap());
+        // sinks are named in the order they're first mentioned, which formatting changes
+        before.sort();
+        after.sort();
+        assert_eq!(before, after);
+    }
+
+    fn cents_to(allocation: &Allocation, sink: &str) -> Vec<(String, i64)> {
+        allocation.transfers.iter().filter(|t| t.sink == sink).map(|t| (t.source.clone(), t.cents)).collect()
+    }
+
+    #[test]
+    fn matches_up_to_the_cap() {
+        let allocation = parse("x needs €500/month
+A commits €200/month
+A -> x
+M matches x 1:1 up to €50/month
+").unwrap().solve();
+        assert_eq!(cents_to(&allocation, "x"), [("A".to_owned(), 20000), ("M".to_owned(), 5000)]);
+        assert!(allocation.transfers[1].matching);
+    }
+
+    #[test]
+    fn matches_until_the_sink_is_full() {
+        let allocation = parse("x needs €300/month
+A commits €500/month
+A -> x
+M matches x 2:1
+").unwrap().solve();
+        assert_eq!(cents_to(&allocation, "x"), [("A".to_owned(), 10000), ("M".to_owned(), 20000)]);
+        assert_eq!(allocation.sinks[0].received_cents, 30000);
+        assert_eq!(allocation.sources[0].unspent_cents, 40000);
+    }
+
+    #[test]
+    fn withholds_from_sinks_that_stay_short() {
+        let allocation = parse("x needs €500/month
+y needs €50/month
+A commits €100/month
+B commits €100/month
+A -> x if funded
+B -> y if funded
+").unwrap().solve();
+        assert_eq!(allocation.sources[0].withheld, ["x"]);
+        assert_eq!(allocation.sources[0].unspent_cents, 10000);
+        assert!(allocation.sources[1].withheld.is_empty());
+        assert_eq!(cents_to(&allocation, "x"), []);
+        assert_eq!(cents_to(&allocation, "y"), [("B".to_owned(), 5000)]);
+    }
+
+    // once B is withheld from y its money goes to x, which still isn't enough with A's
+    #[test]
+    fn withholding_cascades() {
+        let allocation = parse("x needs €220/month
+y needs €150/month
+A commits €110/month
+B commits €60/month
+A -> x if funded
+B -> y || x if funded
+").unwrap().solve();
+        assert_eq!(allocation.sources[0].withheld, ["x"]);
+        assert_eq!(allocation.sources[1].withheld, ["y", "x"]);
+        assert!(allocation.transfers.is_empty());
+        assert_eq!(allocation.sources.iter().map(|s| s.unspent_cents).collect::<Vec<_>>(), [11000, 6000]);
+    }
+}
@@ -0,0 +1,192 @@
+// turns the solver's exact fractions into whole cents without losing or inventing money
+
+use fraction::ToPrimitive;
+
+use crate::Fraction;
+
+// rounds amounts to cents adding up to `total` cents, going up for the largest remainders
+// first and, when the total is below what the amounts floor to, down for the smallest
+fn apportion(amounts: &[Fraction], total: i64) -> Vec<i64> {
+    let (mut res, remainders) = floor_cents(amounts);
+    let mut extra = total - res.iter().sum::<i64>();
+    let order = by_remainder(&remainders);
+
+    while extra > 0 && !order.is_empty() {
+        for &i in &order {
+            if extra == 0 {
+                break
+            }
+            res[i] += 1;
+            extra -= 1;
+        }
+    }
+    while extra < 0 && !order.is_empty() {
+        for &i in order.iter().rev() {
+            if extra == 0 {
+                break
+            }
+            res[i] -= 1;
+            extra += 1;
+        }
+    }
+
+    res
+}
+
+// rounds a source by sink table of transfers to cents so that every source's row adds up to
+// what it sends rounded on its own, and every sink's column as close to what it receives
+// rounded as the rows leave room for, the difference going to the largest remainders.
+// Returns the cents per cell, and per column how many cents it ended up over (positive) or
+// under (negative) what it receives rounded up or down.
+pub(crate) fn round_table(rows: usize, cols: usize, cells: &[(usize, usize, Fraction)]) -> (Vec<i64>, Vec<i64>) {
+    let (mut res, remainders) = floor_cents(&cells.iter().map(|c| c.2.clone()).collect::<Vec<_>>());
+
+    let mut row_totals = vec!(Fraction::from(0); rows);
+    let mut col_totals = vec!(Fraction::from(0); cols);
+    for (r, c, a) in cells {
+        row_totals[*r] += a.clone();
+        col_totals[*c] += a.clone();
+    }
+
+    // how many cells of every row and column still need rounding up
+    let mut row_need: Vec<i64> = row_totals.into_iter().map(|t| to_i64((t * Fraction::from(100)).round())).collect();
+    let mut col_need = apportion(&col_totals, row_need.iter().sum());
+    for (i, (r, c, _)) in cells.iter().enumerate() {
+        row_need[*r] -= res[i];
+        col_need[*c] -= res[i];
+    }
+
+    let order = by_remainder(&remainders);
+    let mut up = vec!(false; cells.len());
+
+    // largest remainders first, as long as both the row and the column still want a cent
+    for &i in &order {
+        let (r, c, _) = cells[i];
+        if remainders[i] > Fraction::from(0) && row_need[r] > 0 && col_need[c] > 0 {
+            up[i] = true;
+            row_need[r] -= 1;
+            col_need[c] -= 1;
+        }
+    }
+
+    // then move earlier choices around to make room for the rows that are still short
+    for (r, need) in row_need.iter_mut().enumerate() {
+        while *need > 0 {
+            let mut seen_cols = vec!(false; cols);
+            match augment(r, cells, &remainders, &up, &col_need, &mut seen_cols) {
+                Some(path) => {
+                    for i in &path {
+                        up[*i] = !up[*i];
+                    }
+                    let last = cells[*path.last().unwrap()].1;
+                    col_need[last] -= 1;
+                    *need -= 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // whatever can't be placed keeps the source's row right and shows up as a column difference
+    for &i in &order {
+        let (r, c, _) = cells[i];
+        if !up[i] && remainders[i] > Fraction::from(0) && row_need[r] > 0 {
+            up[i] = true;
+            row_need[r] -= 1;
+            col_need[c] -= 1;
+        }
+    }
+
+    for (i, u) in up.iter().enumerate() {
+        if *u {
+            res[i] += 1;
+        }
+    }
+
+    // compared with what every sink receives, rounding it either way is fine
+    let mut sums = vec!(0; cols);
+    for (i, (_, c, _)) in cells.iter().enumerate() {
+        sums[*c] += res[i];
+    }
+    let leftover = col_totals.into_iter().zip(sums).map(|(t, sum)| {
+        let cents = t * Fraction::from(100);
+        let (floor, ceil) = (to_i64(cents.floor()), to_i64(cents.ceil()));
+        if sum > ceil {
+            sum - ceil
+        } else if sum < floor {
+            sum - floor
+        } else {
+            0
+        }
+    }).collect();
+
+    (res, leftover)
+}
+
+// finds an alternating path from row r to a column that still wants a cent: cells that
+// get rounded up along it and cells that stop being rounded up to make room, in order
+fn augment(
+    r: usize,
+    cells: &[(usize, usize, Fraction)],
+    remainders: &[Fraction],
+    up: &[bool],
+    col_need: &[i64],
+    seen_cols: &mut [bool],
+) -> Option<Vec<usize>> {
+    for (i, (cr, c, _)) in cells.iter().enumerate() {
+        if *cr != r || up[i] || remainders[i] == Fraction::from(0) || seen_cols[*c] {
+            continue
+        }
+        seen_cols[*c] = true;
+
+        if col_need[*c] > 0 {
+            return Some(vec!(i))
+        }
+
+        // take the cent away from another row in this column, that row then looks elsewhere
+        for (j, (r2, c2, _)) in cells.iter().enumerate() {
+            if c2 == c && up[j] {
+                if let Some(mut path) = augment(*r2, cells, remainders, up, col_need, seen_cols) {
+                    path.insert(0, j);
+                    path.insert(0, i);
+                    return Some(path)
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn floor_cents(amounts: &[Fraction]) -> (Vec<i64>, Vec<Fraction>) {
+    let hundred = Fraction::from(100);
+    let mut res = vec!();
+    let mut remainders = vec!();
+
+    for a in amounts {
+        let cents = a.clone() * hundred.clone();
+        let floor = cents.floor();
+        res.push(to_i64(floor.clone()));
+        remainders.push(cents - floor);
+    }
+
+    (res, remainders)
+}
+
+// indices by descending remainder, ties go to the earlier one so the output is stable
+fn by_remainder(remainders: &[Fraction]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..remainders.len()).collect();
+    order.sort_by(|a, b| remainders[*b].partial_cmp(&remainders[*a]).unwrap().then(a.cmp(b)));
+    order
+}
+
+// amounts, converted ones too, are limited to MAX_DIGITS integer digits when parsed, what the
+// solver adds up from them stays well below where this would saturate
+pub(crate) fn to_i64(whole: Fraction) -> i64 {
+    let n = whole.numer().and_then(|n| n.to_i64()).unwrap_or(i64::MAX);
+    if whole < Fraction::from(0) {
+        -n
+    } else {
+        n
+    }
+}
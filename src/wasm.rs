@@ -0,0 +1,78 @@
+// entry point of the wasm module, the host provides stdin, stdout and stderr through read,
+// write and write_err. It also has to provide exit_code, which is told whether the plan could
+// be solved, hosts written before it was added have to add it to instantiate the module.
+
+use crate::parse_bytes;
+
+fn read_all() -> Vec<u8> {
+    let buf: [u8; 1024] = [0; 1024];
+    let mut res: Vec<u8> = [].to_vec();
+
+    loop {
+        let n;
+        unsafe {
+            n = read(&buf[0], buf.len());
+        }
+
+        if n == 0 {
+            return res
+        }
+
+        res.extend_from_slice(&buf[0 .. n]);
+    }
+}
+
+fn write_bts(bts: &[u8]) {
+    unsafe {
+        write(&bts[0], bts.len());
+    }
+}
+
+// diagnostics go apart from the result, like the command line sends them to stderr
+fn write_err_bts(bts: &[u8]) {
+    unsafe {
+        write_err(&bts[0], bts.len());
+    }
+}
+
+fn main() -> i32 {
+    let bts = read_all();
+    if bts.is_empty() {
+        return 0;
+    }
+
+    let plan = match parse_bytes(&bts) {
+        Ok(v) => v,
+        Err(d) => {
+            write_err_bts(d.to_string().as_bytes());
+            return 1
+        }
+    };
+
+    if !plan.warnings.is_empty() {
+        write_err_bts(format!("{}\n", plan.warnings).as_bytes());
+    }
+
+    match plan.months() {
+        Some(n) => write_bts(plan.simulate(n).to_string().as_bytes()),
+        None => write_bts(plan.solve().to_string().as_bytes()),
+    }
+
+    0
+}
+
+extern "C" {
+    fn read(p: *const u8, l: usize) -> usize;
+    fn write(p: *const u8, l: usize);
+    fn write_err(p: *const u8, l: usize);
+    // 0 when the plan was solved, 1 when the file had errors
+    fn exit_code(code: i32);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let code = main();
+    unsafe {
+        exit_code(code);
+    }
+}
\ No newline at end of file